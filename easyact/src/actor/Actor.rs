@@ -361,7 +361,8 @@ pub trait Actor: Sized + Send + Sync + 'static {
                     println!("parent cancelled. we kill ourself");
                     return Ok(());
                 }
-                _ = &mut timer_wakeup => {
+                // an OptionFuture without a timer resolves instantly, so actors without a timer must skip it.
+                _ = &mut timer_wakeup, if self.timer_duration().is_some() => {
                     match self.on_timer().await {
                         Ok(_) => {},
                         Err(e) => {
@@ -1,13 +1,57 @@
-use anyhow::Context;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, Context};
 use serde::{Serialize, Deserialize};
+use crate::config_interpolation::{Interpolator, Redactor};
+use crate::container_labels::DEFAULT_NAMESPACE;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+pub const HEALTH_CHECK_TYPES: [&str; 1] = ["http"];
+
+pub const DEFAULT_STOP_GRACE_PERIOD_S: u32 = 10;
+
+// namespaces end up in docker labels and lock file names.
+const MAX_NAMESPACE_LEN: usize = 63;
+
+// errors of a config that failed to load are logged and shown in the status, secrets of the config must not leak into them.
+fn redact_error(redactor: &Redactor, e: ::anyhow::Error) -> ::anyhow::Error {
+    anyhow!(redactor.redact(&format!("{:#}", e)))
+}
+
+fn is_valid_namespace(namespace: &str) -> bool {
+    !namespace.is_empty()
+        && namespace.len() <= MAX_NAMESPACE_LEN
+        && namespace.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
+fn default_stop_grace_period_s() -> u32 {
+    DEFAULT_STOP_GRACE_PERIOD_S
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct ConfigFile {
+    // separates easyharun instances sharing one container runtime, every fragment setting it must agree.
+    #[serde(default)]
+    pub namespace: Option<String>,
+    // files or directories (all *.toml files inside), relative to this file.
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default)]
     pub proxy: Vec<ConfigFileProxy>,
+    #[serde(default)]
     pub container: Vec<ConfigFileContainer>,
+    #[serde(default)]
     pub health_check: Vec<ConfigFileHealthCheck>
 }
 
+// a single parsed file, a config could be split across many of them.
+#[derive(Debug, Clone)]
+pub struct ConfigFragment {
+    pub file: String,
+    pub config_file: ConfigFile,
+    pub redactor: Redactor,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ConfigFileHealthCheck {
     pub name: String,
@@ -30,6 +74,77 @@ pub struct ConfigFileContainer {
     pub container_ports: Vec<u32>,
     pub health_checks: Vec<String>,
     pub proxies: Vec<ConfigContainerProxy>,
+    // seconds docker waits after SIGTERM before the container gets killed.
+    #[serde(default = "default_stop_grace_period_s")]
+    pub stop_grace_period_s: u32,
+    // a replica is replaced after this many failed health checks in a row ...
+    #[serde(default)]
+    pub restart_after_failures: Option<u32>,
+    // ... once it has been unhealthy for at least this many seconds.
+    #[serde(default)]
+    pub unhealthy_grace_s: u32,
+    #[serde(default)]
+    pub update: ConfigContainerUpdate,
+    #[serde(default)]
+    pub canary: Option<ConfigContainerCanary>,
+    #[serde(flatten)]
+    pub runtime: ConfigContainerRuntime,
+}
+
+// how replicas are replaced when the spec of a container changes.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ConfigContainerUpdate {
+    #[serde(default)]
+    pub strategy: ConfigContainerUpdateStrategy,
+    // replicas that may be started on top of the configured ones.
+    #[serde(default = "default_max_surge")]
+    pub max_surge: u32,
+    // replicas that may be not ready while replacing them.
+    #[serde(default)]
+    pub max_unavailable: u32,
+    // replicas of a new revision that are not ready after this time are rolled back to the last healthy revision.
+    #[serde(default)]
+    pub rollback_after_s: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfigContainerUpdateStrategy {
+    // replaces replica by replica, see max_surge and max_unavailable.
+    #[default]
+    Rolling,
+    // starts all replicas of the new revision next to the old ones, the proxies switch once all of them are healthy.
+    BlueGreen,
+}
+
+// replicas of another image that run next to the configured ones and get a share of the traffic.
+// remove it to abort, or move its image to the container to promote it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ConfigContainerCanary {
+    pub image: String,
+    #[serde(default = "default_canary_replicas")]
+    pub replicas: u32,
+    // percent of the traffic of the container's proxies.
+    pub weight: u32,
+}
+
+fn default_canary_replicas() -> u32 {
+    1
+}
+
+fn default_max_surge() -> u32 {
+    1
+}
+
+impl Default for ConfigContainerUpdate {
+    fn default() -> Self {
+        Self {
+            strategy: ConfigContainerUpdateStrategy::default(),
+            max_surge: default_max_surge(),
+            max_unavailable: 0,
+            rollback_after_s: None,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
@@ -38,6 +153,67 @@ pub struct ConfigContainerProxy {
     pub name: String,
 }
 
+// settings that are passed through to the container runtime when a container is created.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ConfigContainerRuntime {
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    #[serde(default)]
+    pub command: Option<Vec<String>>,
+    #[serde(default)]
+    pub entrypoint: Option<Vec<String>>,
+    #[serde(default)]
+    pub volumes: Vec<ConfigContainerVolume>,
+    #[serde(default)]
+    pub working_dir: Option<String>,
+    #[serde(default)]
+    pub user: Option<String>,
+    #[serde(default)]
+    pub memory_mb: Option<u64>,
+    #[serde(default)]
+    pub cpus: Option<f64>,
+    #[serde(default)]
+    pub ulimits: Vec<ConfigContainerUlimit>,
+    // labels starting with easyharun are reserved.
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+}
+
+impl ConfigContainerRuntime {
+    // env values can be secrets, only the keys are kept where others can read them.
+    pub fn without_env_values(&self) -> Self {
+        Self {
+            env: self.env.keys().map(|k| (k.to_string(), String::new())).collect(),
+            ..self.clone()
+        }
+    }
+}
+
+// source is a host path (bind mount) or the name of a volume.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ConfigContainerVolume {
+    pub source: String,
+    pub target: String,
+    #[serde(default)]
+    pub read_only: bool,
+}
+
+impl ConfigContainerVolume {
+    pub fn to_bind(&self) -> String {
+        match self.read_only {
+            true => format!("{}:{}:ro", self.source, self.target),
+            false => format!("{}:{}", self.source, self.target),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ConfigContainerUlimit {
+    pub name: String,
+    pub soft: i64,
+    pub hard: i64,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct ConfigContainer {
     pub name: String,
@@ -46,48 +222,659 @@ pub struct ConfigContainer {
     pub container_ports: Vec<u32>,
     pub health_checks: Vec<String>,
     pub proxies: Vec<ConfigContainerProxy>,
+    pub stop_grace_period_s: u32,
+    pub restart_after_failures: Option<u32>,
+    pub unhealthy_grace_s: u32,
+    pub update: ConfigContainerUpdate,
+    // percent of the traffic, only set for canary replicas.
+    pub canary_weight: Option<u32>,
+    pub runtime: ConfigContainerRuntime,
 }
 
 #[derive(Clone, Debug, Default)]
 pub struct Config {
+    pub namespace: Option<String>,
     pub proxy: Vec<ConfigFileProxy>,
     pub container: Vec<ConfigContainer>,
-    pub health_check: Vec<ConfigFileHealthCheck>
+    pub health_check: Vec<ConfigFileHealthCheck>,
+    // all files and directories the config was read from.
+    pub sources: Vec<String>,
+    // knows the secrets of the config, use it before logging or exposing config values.
+    pub redactor: Redactor,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ConfigValidationError {
+    pub file: String,
+    pub field: String,
+    pub message: String,
+}
+
+impl Display for ConfigValidationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}: {}", self.file, self.field, self.message)
+    }
+}
+
+impl ConfigFile {
+
+    pub fn validate(&self, file: &str) -> Vec<ConfigValidationError> {
+        ConfigFragment::validate_all(&[ConfigFragment {
+            file: file.to_string(),
+            config_file: self.clone(),
+            redactor: Redactor::default(),
+        }])
+    }
+}
+
+impl ConfigFragment {
+
+    // validates the merged fragments, returns every problem, not only the first one.
+    pub fn validate_all(fragments: &[ConfigFragment]) -> Vec<ConfigValidationError> {
+        let mut errors = vec![];
+
+        let mut error = |file: &str, field: String, message: String| errors.push(ConfigValidationError {
+            file: file.to_string(),
+            field,
+            message,
+        });
+
+        let mut proxy_names = HashMap::new();
+        let mut proxy_listen_addrs = HashMap::new();
+        let mut health_check_names = HashMap::new();
+        let mut container_names = HashMap::new();
+        let mut namespace: Option<(&str, &str)> = None;
+
+        for fragment in fragments {
+            let file = fragment.file.as_str();
+
+            if let Some(fragment_namespace) = &fragment.config_file.namespace {
+                if !is_valid_namespace(fragment_namespace) {
+                    error(file, "namespace".to_string(), format!("namespace \"{}\" must be 1 to {} characters of a-z, 0-9, - and _", fragment_namespace, MAX_NAMESPACE_LEN));
+                }
+
+                match namespace {
+                    Some((other, other_file)) if other != fragment_namespace => error(file, "namespace".to_string(), format!("namespace \"{}\" conflicts with namespace \"{}\" of {}", fragment_namespace, other, other_file)),
+                    Some(_) => {},
+                    None => namespace = Some((fragment_namespace.as_str(), file)),
+                };
+            }
+
+            for (i, proxy) in fragment.config_file.proxy.iter().enumerate() {
+                let location = format!("{} proxy[{}]", file, i);
+
+                match proxy_names.get(proxy.name.as_str()) {
+                    Some(other) => error(file, format!("proxy[{}].name", i), format!("proxy name \"{}\" is not unique, already defined in {}", proxy.name, other)),
+                    None => { proxy_names.insert(proxy.name.as_str(), location.clone()); },
+                };
+
+                match proxy_listen_addrs.get(proxy.listen.as_str()) {
+                    Some(other) => error(file, format!("proxy[{}].listen", i), format!("listen address \"{}\" is already used by {}", proxy.listen, other)),
+                    None => { proxy_listen_addrs.insert(proxy.listen.as_str(), location); },
+                };
+            }
+
+            for (i, health_check) in fragment.config_file.health_check.iter().enumerate() {
+                match health_check_names.get(health_check.name.as_str()) {
+                    Some(other) => error(file, format!("health_check[{}].name", i), format!("health_check name \"{}\" is not unique, already defined in {}", health_check.name, other)),
+                    None => { health_check_names.insert(health_check.name.as_str(), format!("{} health_check[{}]", file, i)); },
+                };
+
+                if !HEALTH_CHECK_TYPES.contains(&health_check.check.as_str()) {
+                    error(file, format!("health_check[{}].check", i), format!("unknown check type \"{}\", expected one of {:?}", health_check.check, HEALTH_CHECK_TYPES));
+                }
+            }
+
+            for (i, container) in fragment.config_file.container.iter().enumerate() {
+                match container_names.get(container.name.as_str()) {
+                    Some(other) => error(file, format!("container[{}].name", i), format!("container name \"{}\" is not unique, already defined in {}", container.name, other)),
+                    None => { container_names.insert(container.name.as_str(), format!("{} container[{}]", file, i)); },
+                };
+            }
+        }
+
+        // references could point into any fragment, so we check them once all names are known.
+        for fragment in fragments {
+            let file = fragment.file.as_str();
+
+            for (i, container) in fragment.config_file.container.iter().enumerate() {
+                for (j, health_check) in container.health_checks.iter().enumerate() {
+                    if !health_check_names.contains_key(health_check.as_str()) {
+                        error(file, format!("container[{}].health_checks[{}]", i, j), format!("health_check \"{}\" does not exist", health_check));
+                    }
+                }
+
+                for (j, proxy) in container.proxies.iter().enumerate() {
+                    if !proxy_names.contains_key(proxy.name.as_str()) {
+                        error(file, format!("container[{}].proxies[{}].name", i, j), format!("proxy \"{}\" does not exist", proxy.name));
+                    }
+
+                    if !container.container_ports.contains(&proxy.container_port) {
+                        error(file, format!("container[{}].proxies[{}].container_port", i, j), format!("port {} is not part of container_ports", proxy.container_port));
+                    }
+                }
+
+                if container.update.strategy == ConfigContainerUpdateStrategy::Rolling && container.update.max_surge == 0 && container.update.max_unavailable == 0 {
+                    error(file, format!("container[{}].update", i), "max_surge and max_unavailable must not both be 0".to_string());
+                }
+
+                if let Some(canary) = &container.canary {
+                    if canary.weight == 0 || canary.weight >= 100 {
+                        error(file, format!("container[{}].canary.weight", i), format!("weight {} must be between 1 and 99", canary.weight));
+                    }
+
+                    if canary.replicas == 0 {
+                        error(file, format!("container[{}].canary.replicas", i), "replicas must be greater than 0".to_string());
+                    }
+
+                    if canary.image == container.image {
+                        error(file, format!("container[{}].canary.image", i), "the canary image must differ from the container image".to_string());
+                    }
+
+                    if container.update.strategy == ConfigContainerUpdateStrategy::BlueGreen {
+                        error(file, format!("container[{}].canary", i), "a canary can not be combined with the blue_green strategy".to_string());
+                    }
+                }
+
+                if container.restart_after_failures == Some(0) {
+                    error(file, format!("container[{}].restart_after_failures", i), "restart_after_failures must be greater than 0".to_string());
+                }
+
+                if container.update.rollback_after_s == Some(0) {
+                    error(file, format!("container[{}].update.rollback_after_s", i), "rollback_after_s must be greater than 0".to_string());
+                }
+
+                Self::validate_container_runtime(&container.runtime, &format!("container[{}]", i), &mut |field, message| error(file, field, message));
+            }
+        }
+
+        errors
+    }
+
+    fn validate_container_runtime(runtime: &ConfigContainerRuntime, prefix: &str, error: &mut dyn FnMut(String, String)) {
+        if runtime.memory_mb == Some(0) {
+            error(format!("{}.memory_mb", prefix), "memory_mb must be greater than 0".to_string());
+        }
+
+        if let Some(cpus) = runtime.cpus {
+            if cpus <= 0.0 {
+                error(format!("{}.cpus", prefix), "cpus must be greater than 0".to_string());
+            }
+        }
+
+        for (i, volume) in runtime.volumes.iter().enumerate() {
+            if volume.source.is_empty() {
+                error(format!("{}.volumes[{}].source", prefix, i), "source must not be empty".to_string());
+            }
+
+            if !volume.target.starts_with('/') {
+                error(format!("{}.volumes[{}].target", prefix, i), format!("target \"{}\" must be an absolute path", volume.target));
+            }
+        }
+
+        for (i, ulimit) in runtime.ulimits.iter().enumerate() {
+            if ulimit.soft > ulimit.hard {
+                error(format!("{}.ulimits[{}].soft", prefix, i), format!("soft limit {} is greater than the hard limit {}", ulimit.soft, ulimit.hard));
+            }
+        }
+
+        for label in runtime.labels.keys() {
+            if label.starts_with("easyharun") {
+                error(format!("{}.labels.{}", prefix, label), "labels starting with easyharun are reserved".to_string());
+            }
+        }
+    }
+
+    async fn read_from_file(file: &Path) -> Result<Self, ::anyhow::Error> {
+        let file_name = file.to_string_lossy().to_string();
+
+        let contents = ::tokio::fs::read(file).await.context(format!("reading file {}", &file_name))?;
+        let contents = String::from_utf8(contents).context(format!("config file {} does not contains vaild uft8", &file_name))?;
+
+        // parsing the raw file first gives errors with line numbers.
+        ::toml::from_str::<ConfigFile>(&contents).context(format!("could not parse toml file {}", &file_name))?;
+
+        // interpolation only touches strings, so the file still has the same shape afterwards.
+        let mut value = ::toml::from_str::<::toml::Value>(&contents).context(format!("could not parse toml file {}", &file_name))?;
+
+        let redactor = match Interpolator::interpolate_with_env(&file_name, &mut value) {
+            Ok(s) => s,
+            Err(errors) => return Err(anyhow!(
+                "config file {} could not be interpolated:\n{}",
+                &file_name,
+                errors.iter().map(|e| e.to_string()).collect::<Vec<_>>().join("\n")
+            )),
+        };
+
+        let config_file = value.try_into::<ConfigFile>()
+            .context(format!("could not parse toml file {}", &file_name))
+            .map_err(|e| redact_error(&redactor, e))?;
+
+        Ok(ConfigFragment {
+            file: file_name,
+            config_file,
+            redactor,
+        })
+    }
+
+    async fn read_toml_files_from_dir(dir: &Path) -> Result<Vec<PathBuf>, ::anyhow::Error> {
+        let mut files = vec![];
+
+        let mut entries = ::tokio::fs::read_dir(dir).await.context(format!("reading directory {}", dir.to_string_lossy()))?;
+        while let Some(entry) = entries.next_entry().await.context(format!("reading directory {}", dir.to_string_lossy()))? {
+            let path = entry.path();
+            if path.is_file() && path.extension().map(|e| e == "toml").unwrap_or(false) {
+                files.push(path);
+            }
+        }
+
+        // fragments are merged in a stable order.
+        files.sort();
+
+        Ok(files)
+    }
+
+    // reads the file (or all *.toml files of the directory) and everything it includes.
+    // returns the fragments and all files and directories that were read.
+    pub async fn read_all(path: &Path) -> Result<(Vec<ConfigFragment>, Vec<String>), ::anyhow::Error> {
+        let mut redactor = Redactor::default();
+
+        // include paths are interpolated, they could contain secrets.
+        Self::read_all_with_redactor(path, &mut redactor).await.map_err(|e| redact_error(&redactor, e))
+    }
+
+    async fn read_all_with_redactor(path: &Path, redactor: &mut Redactor) -> Result<(Vec<ConfigFragment>, Vec<String>), ::anyhow::Error> {
+        let mut fragments = vec![];
+        let mut sources = vec![];
+        let mut visited = HashSet::new();
+        let mut pending = vec![path.to_path_buf()];
+
+        while let Some(path) = pending.pop() {
+            let canonical_path = ::tokio::fs::canonicalize(&path).await.context(format!("could not find {}", path.to_string_lossy()))?;
+
+            // includes could form a cycle or point to the same file twice.
+            if !visited.insert(canonical_path) {
+                continue;
+            }
+
+            sources.push(path.to_string_lossy().to_string());
+
+            if path.is_dir() {
+                pending.extend(Self::read_toml_files_from_dir(&path).await?.into_iter().rev());
+                continue;
+            }
+
+            let fragment = Self::read_from_file(&path).await?;
+            redactor.extend(&fragment.redactor);
+
+            let base_dir = path.parent().map(|p| p.to_path_buf()).unwrap_or_default();
+            pending.extend(fragment.config_file.include.iter().rev().map(|include| base_dir.join(include)));
+
+            fragments.push(fragment);
+        }
+
+        Ok((fragments, sources))
+    }
 }
 
 impl Config {
 
+    pub fn get_namespace(&self) -> &str {
+        self.namespace.as_deref().unwrap_or(DEFAULT_NAMESPACE)
+    }
+
+    // file could also be a directory, then every *.toml file inside is part of the config.
     pub async fn read_from_file(file : &str) -> Result<Self, ::anyhow::Error> {
-        let contents = ::tokio::fs::read(file).await.context(format!("reading file {}", &file))?;
+        let (fragments, sources) = ConfigFragment::read_all(Path::new(file)).await?;
+
+        let errors = ConfigFragment::validate_all(&fragments);
+        if !errors.is_empty() {
+            // the messages quote config values, the config is not loaded so its redactor is the only one knowing the secrets.
+            let mut redactor = Redactor::default();
+            fragments.iter().for_each(|f| redactor.extend(&f.redactor));
+
+            return Err(anyhow!(
+                "config {} is invalid:\n{}",
+                &file,
+                redactor.redact(&errors.iter().map(|e| e.to_string()).collect::<Vec<_>>().join("\n"))
+            ));
+        }
 
-        let config_file = ::toml::from_str::<ConfigFile>(
-            &String::from_utf8(contents).context(format!("config file {} does not contains vaild uft8", &file))?
-        ).context(format!("could not parse toml file {}", &file))?;
+        Ok(Self::from_fragments(fragments, sources))
+    }
+
+    pub fn from_fragments(fragments: Vec<ConfigFragment>, sources: Vec<String>) -> Self {
+        let mut config = Config {
+            sources,
+            ..Config::default()
+        };
 
+        for fragment in fragments {
+            config.redactor.extend(&fragment.redactor);
 
-        let container = {
-            let mut buffer = vec![];
+            let config_file = fragment.config_file;
+
+            if config_file.namespace.is_some() {
+                config.namespace = config_file.namespace;
+            }
 
             for config_file_container in config_file.container {
                 for replica_id in 0..config_file_container.replicas {
-                    buffer.push(ConfigContainer {
+                    config.container.push(ConfigContainer {
                         replica_id,
                         proxies: config_file_container.proxies.clone(),
                         name: config_file_container.name.clone(),
                         image: config_file_container.image.clone(),
                         health_checks: config_file_container.health_checks.clone(),
-                        container_ports: config_file_container.container_ports.clone()
+                        container_ports: config_file_container.container_ports.clone(),
+                        stop_grace_period_s: config_file_container.stop_grace_period_s,
+                        restart_after_failures: config_file_container.restart_after_failures,
+                        unhealthy_grace_s: config_file_container.unhealthy_grace_s,
+                        update: config_file_container.update.clone(),
+                        canary_weight: None,
+                        runtime: config_file_container.runtime.clone(),
                     })
                 }
+
+                if let Some(canary) = &config_file_container.canary {
+                    for replica_id in 0..canary.replicas {
+                        config.container.push(ConfigContainer {
+                            replica_id,
+                            proxies: config_file_container.proxies.clone(),
+                            name: config_file_container.name.clone(),
+                            image: canary.image.clone(),
+                            health_checks: config_file_container.health_checks.clone(),
+                            container_ports: config_file_container.container_ports.clone(),
+                            stop_grace_period_s: config_file_container.stop_grace_period_s,
+                            restart_after_failures: config_file_container.restart_after_failures,
+                            unhealthy_grace_s: config_file_container.unhealthy_grace_s,
+                            update: config_file_container.update.clone(),
+                            canary_weight: Some(canary.weight),
+                            runtime: config_file_container.runtime.clone(),
+                        })
+                    }
+                }
             }
 
-            buffer
+            config.proxy.extend(config_file.proxy);
+            config.health_check.extend(config_file.health_check);
+        }
+
+        config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(toml: &str) -> ConfigFile {
+        ::toml::from_str::<ConfigFile>(toml).expect("valid toml")
+    }
+
+    #[test]
+    fn validate_valid_config() {
+        let config_file = parse(r#"
+            [[proxy]]
+            name = "http"
+            listen = "0.0.0.0:89"
+
+            [[container]]
+            replicas = 1
+            name = "nginx"
+            image = "nginx:latest"
+            container_ports = [80]
+            health_checks = ["http"]
+            proxies = [{name = "http", container_port = 80}]
+            update = {strategy = "blue_green", max_surge = 0}
+
+            [[health_check]]
+            name = "http"
+            check = "http"
+            url = "http://127.0.0.1:{{container.port_dynamic_host_80}}"
+            timeout_ms = 1000
+        "#);
+
+        assert_eq!(Vec::<ConfigValidationError>::new(), config_file.validate("easyharun.toml"));
+    }
+
+    #[test]
+    fn validate_reports_all_errors() {
+        let config_file = parse(r#"
+            [[proxy]]
+            name = "http"
+            listen = "0.0.0.0:89"
+
+            [[proxy]]
+            name = "http"
+            listen = "0.0.0.0:89"
+
+            [[container]]
+            replicas = 1
+            name = "nginx"
+            image = "nginx:latest"
+            container_ports = [80]
+            health_checks = ["missing"]
+            proxies = [{name = "other", container_port = 81}]
+
+            [[health_check]]
+            name = "http"
+            check = "tcp"
+            url = "http://127.0.0.1"
+            timeout_ms = 1000
+        "#);
+
+        let fields = config_file.validate("easyharun.toml").into_iter().map(|e| e.field).collect::<Vec<_>>();
+
+        assert_eq!(vec![
+            "proxy[1].name",
+            "proxy[1].listen",
+            "health_check[0].check",
+            "container[0].health_checks[0]",
+            "container[0].proxies[0].name",
+            "container[0].proxies[0].container_port",
+        ], fields);
+    }
+
+    #[test]
+    fn validate_detects_conflicts_across_fragments() {
+        let fragments = vec![
+            ConfigFragment {
+                file: "a.toml".to_string(),
+                config_file: parse(r#"
+                    [[proxy]]
+                    name = "http"
+                    listen = "0.0.0.0:89"
+                "#),
+                redactor: Redactor::default(),
+            },
+            ConfigFragment {
+                file: "b.toml".to_string(),
+                config_file: parse(r#"
+                    [[proxy]]
+                    name = "http"
+                    listen = "0.0.0.0:90"
+
+                    [[container]]
+                    replicas = 1
+                    name = "nginx"
+                    image = "nginx:latest"
+                    container_ports = [80]
+                    health_checks = []
+                    proxies = [{name = "http", container_port = 80}]
+                "#),
+                redactor: Redactor::default(),
+            },
+        ];
+
+        let errors = ConfigFragment::validate_all(&fragments);
+
+        assert_eq!(1, errors.len());
+        assert_eq!("b.toml", errors[0].file);
+        assert_eq!("proxy[0].name", errors[0].field);
+        assert!(errors[0].message.contains("a.toml proxy[0]"));
+    }
+
+    #[tokio::test]
+    async fn read_from_file_merges_includes_and_directories() {
+        let dir = ::std::env::temp_dir().join(format!("easyharun_config_test_{}", ::std::process::id()));
+        let conf_d = dir.join("conf.d");
+        ::std::fs::create_dir_all(&conf_d).expect("create dir");
+
+        ::std::fs::write(dir.join("easyharun.toml"), r#"
+            include = ["conf.d"]
+
+            [[proxy]]
+            name = "http"
+            listen = "0.0.0.0:89"
+
+            [[health_check]]
+            name = "http"
+            check = "http"
+            url = "http://127.0.0.1:{{container.port_dynamic_host_80}}"
+            timeout_ms = 1000
+        "#).expect("write");
+
+        ::std::fs::write(conf_d.join("nginx.toml"), r#"
+            [[container]]
+            replicas = 2
+            name = "nginx"
+            image = "nginx:latest"
+            container_ports = [80]
+            health_checks = ["http"]
+            proxies = [{name = "http", container_port = 80}]
+        "#).expect("write");
+
+        ::std::fs::write(conf_d.join("README.md"), "not a config").expect("write");
+
+        let config = Config::read_from_file(&dir.join("easyharun.toml").to_string_lossy()).await.expect("valid config");
+
+        ::std::fs::remove_dir_all(&dir).expect("cleanup");
+
+        assert_eq!(1, config.proxy.len());
+        assert_eq!(1, config.health_check.len());
+        assert_eq!(2, config.container.len());
+        assert_eq!(3, config.sources.len());
+    }
+
+    #[tokio::test]
+    async fn errors_of_an_invalid_config_are_redacted() {
+        let dir = ::std::env::temp_dir().join(format!("easyharun_config_redact_test_{}", ::std::process::id()));
+        ::std::fs::create_dir_all(&dir).expect("create dir");
+
+        let secret = dir.join("listen");
+        ::std::fs::write(&secret, "10.0.0.1:4711").expect("write");
+
+        ::std::fs::write(dir.join("easyharun.toml"), format!(r#"
+            [[proxy]]
+            name = "a"
+            listen = "${{file:{0}}}"
+
+            [[proxy]]
+            name = "b"
+            listen = "${{file:{0}}}"
+        "#, secret.to_string_lossy())).expect("write");
+
+        let e = Config::read_from_file(&dir.join("easyharun.toml").to_string_lossy()).await.expect_err("duplicate listen address");
+
+        ::std::fs::remove_dir_all(&dir).expect("cleanup");
+
+        assert!(format!("{:#}", e).contains("listen address \"***\" is already used"));
+        assert!(!format!("{:#}", e).contains("10.0.0.1:4711"));
+    }
+
+    #[test]
+    fn parse_and_validate_container_runtime() {
+        let config_file = parse(r#"
+            [[container]]
+            replicas = 1
+            name = "nginx"
+            image = "nginx:latest"
+            container_ports = [80]
+            health_checks = []
+            proxies = []
+            env = { FOO = "bar" }
+            command = ["nginx", "-g", "daemon off;"]
+            volumes = [{source = "/srv/www", target = "/usr/share/nginx/html", read_only = true}, {source = "data", target = "relative"}]
+            memory_mb = 256
+            cpus = 0.5
+            ulimits = [{name = "nofile", soft = 2048, hard = 1024}]
+            labels = { "easyharun_name" = "other", "team" = "web" }
+
+            [container.update]
+            max_surge = 0
+        "#);
+
+        let runtime = &config_file.container[0].runtime;
+        assert_eq!(Some(&"bar".to_string()), runtime.env.get("FOO"));
+        assert_eq!("/srv/www:/usr/share/nginx/html:ro", runtime.volumes[0].to_bind());
+        assert_eq!(Some(256), runtime.memory_mb);
+        assert_eq!(Some(&String::new()), runtime.without_env_values().env.get("FOO"));
+        assert_eq!(0, config_file.container[0].update.max_unavailable);
+        assert_eq!(ConfigContainerUpdateStrategy::Rolling, config_file.container[0].update.strategy);
+
+        let fields = config_file.validate("easyharun.toml").into_iter().map(|e| e.field).collect::<Vec<_>>();
+
+        assert_eq!(vec![
+            "container[0].update",
+            "container[0].volumes[1].target",
+            "container[0].ulimits[0].soft",
+            "container[0].labels.easyharun_name",
+        ], fields);
+    }
+
+    #[test]
+    fn fragments_agree_on_one_namespace() {
+        let fragment = |file: &str, toml: &str| ConfigFragment {
+            file: file.to_string(),
+            config_file: parse(toml),
+            redactor: Redactor::default(),
         };
 
-        Ok(Config {
-            proxy: config_file.proxy,
-            health_check: config_file.health_check,
-            container
-        })
+        let fragments = vec![
+            fragment("easyharun.toml", r#"namespace = "staging""#),
+            fragment("web.toml", ""),
+            fragment("db.toml", r#"namespace = "staging""#),
+        ];
+
+        assert_eq!(Vec::<ConfigValidationError>::new(), ConfigFragment::validate_all(&fragments));
+        assert_eq!("staging", Config::from_fragments(fragments, vec![]).get_namespace());
+        assert_eq!(DEFAULT_NAMESPACE, Config::from_fragments(vec![fragment("easyharun.toml", "")], vec![]).get_namespace());
+
+        let errors = ConfigFragment::validate_all(&[
+            fragment("easyharun.toml", r#"namespace = "staging""#),
+            fragment("web.toml", r#"namespace = "Prod!""#),
+        ]);
+
+        assert_eq!(vec![("web.toml", "namespace"), ("web.toml", "namespace")], errors.iter().map(|e| (e.file.as_str(), e.field.as_str())).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn canary_replicas_are_added_to_the_container() {
+        let config_file = parse(r#"
+            [[container]]
+            replicas = 2
+            name = "nginx"
+            image = "nginx:1.24"
+            container_ports = [80]
+            health_checks = []
+            proxies = []
+            canary = {image = "nginx:1.25", weight = 10}
+        "#);
+
+        assert_eq!(Vec::<ConfigValidationError>::new(), config_file.validate("easyharun.toml"));
+
+        let config = Config::from_fragments(vec![ConfigFragment {
+            file: "easyharun.toml".to_string(),
+            config_file,
+            redactor: Redactor::default(),
+        }], vec![]);
+
+        let canaries = config.container.iter().filter(|c| c.canary_weight.is_some()).collect::<Vec<_>>();
+
+        assert_eq!(3, config.container.len());
+        assert_eq!(1, canaries.len());
+        assert_eq!("nginx:1.25", canaries[0].image);
+        assert_eq!(Some(10), canaries[0].canary_weight);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,249 @@
+use std::fmt::{Debug, Formatter};
+use toml::Value;
+use crate::config::ConfigValidationError;
+
+// Replaces secrets read by ${file:...} before text is logged or shown in a status.
+#[derive(Clone, Default)]
+pub struct Redactor {
+    secrets: Vec<String>,
+}
+
+impl Debug for Redactor {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Redactor {{ secrets: {} }}", self.secrets.len())
+    }
+}
+
+impl Redactor {
+    pub fn add_secret(&mut self, secret: String) {
+        if secret.is_empty() || self.secrets.contains(&secret) {
+            return;
+        }
+
+        self.secrets.push(secret);
+        // longer secrets first, a secret could contain another one.
+        self.secrets.sort_by_key(|s| ::std::cmp::Reverse(s.len()));
+    }
+
+    pub fn extend(&mut self, other: &Redactor) {
+        for secret in other.secrets.iter() {
+            self.add_secret(secret.to_string());
+        }
+    }
+
+    pub fn redact(&self, text: &str) -> String {
+        let mut text = text.to_string();
+
+        for secret in self.secrets.iter() {
+            text = text.replace(secret.as_str(), "***");
+        }
+
+        text
+    }
+}
+
+// Supports ${VAR}, ${VAR:-default} and ${file:/path/to/secret} in every string value of a config file.
+// $${ is kept as a literal ${.
+pub struct Interpolator<'a> {
+    file: &'a str,
+    env: &'a dyn Fn(&str) -> Option<String>,
+    errors: Vec<ConfigValidationError>,
+    redactor: Redactor,
+}
+
+impl<'a> Interpolator<'a> {
+
+    pub fn interpolate_with_env(file: &str, value: &mut Value) -> Result<Redactor, Vec<ConfigValidationError>> {
+        Self::interpolate(file, value, &|name| ::std::env::var(name).ok())
+    }
+
+    pub fn interpolate(file: &str, value: &mut Value, env: &dyn Fn(&str) -> Option<String>) -> Result<Redactor, Vec<ConfigValidationError>> {
+        let mut interpolator = Interpolator {
+            file,
+            env,
+            errors: vec![],
+            redactor: Redactor::default(),
+        };
+
+        interpolator.interpolate_value("", value);
+
+        if !interpolator.errors.is_empty() {
+            return Err(interpolator.errors);
+        }
+
+        Ok(interpolator.redactor)
+    }
+
+    fn interpolate_value(&mut self, field: &str, value: &mut Value) {
+        match value {
+            Value::String(s) => {
+                if let Some(interpolated) = self.interpolate_string(field, s) {
+                    *s = interpolated;
+                }
+            },
+            Value::Array(values) => {
+                for (i, value) in values.iter_mut().enumerate() {
+                    self.interpolate_value(&format!("{}[{}]", field, i), value);
+                }
+            },
+            Value::Table(table) => {
+                for (key, value) in table.iter_mut() {
+                    let field = match field {
+                        "" => key.to_string(),
+                        _ => format!("{}.{}", field, key),
+                    };
+
+                    self.interpolate_value(&field, value);
+                }
+            },
+            _ => {},
+        };
+    }
+
+    fn error(&mut self, field: &str, message: String) {
+        self.errors.push(ConfigValidationError {
+            file: self.file.to_string(),
+            field: field.to_string(),
+            message,
+        });
+    }
+
+    fn interpolate_string(&mut self, field: &str, input: &str) -> Option<String> {
+        let mut output = String::new();
+        let mut rest = input;
+
+        while let Some(start) = rest.find('$') {
+            output.push_str(&rest[..start]);
+            rest = &rest[start..];
+
+            if rest.starts_with("$${") {
+                output.push_str("${");
+                rest = &rest[3..];
+                continue;
+            }
+
+            if !rest.starts_with("${") {
+                output.push('$');
+                rest = &rest[1..];
+                continue;
+            }
+
+            let end = match rest.find('}') {
+                Some(s) => s,
+                None => {
+                    self.error(field, format!("unterminated \"${{\" in \"{}\"", input));
+                    return None;
+                }
+            };
+
+            let expression = &rest[2..end];
+            rest = &rest[end + 1..];
+
+            output.push_str(&self.resolve(field, expression)?);
+        }
+
+        output.push_str(rest);
+
+        Some(output)
+    }
+
+    fn resolve(&mut self, field: &str, expression: &str) -> Option<String> {
+        if let Some(path) = expression.strip_prefix("file:") {
+            return match ::std::fs::read_to_string(path) {
+                Ok(s) => {
+                    let secret = s.trim_end_matches(['\n', '\r']).to_string();
+                    self.redactor.add_secret(secret.clone());
+                    Some(secret)
+                },
+                Err(e) => {
+                    self.error(field, format!("could not read secret file {}: {}", path, e));
+                    None
+                }
+            };
+        }
+
+        let (name, default) = match expression.split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (expression, None),
+        };
+
+        if name.is_empty() {
+            self.error(field, "empty variable name in \"${}\"".to_string());
+            return None;
+        }
+
+        match ((self.env)(name), default) {
+            (Some(v), Some(default)) if v.is_empty() => Some(default.to_string()),
+            (Some(v), _) => Some(v),
+            (None, Some(default)) => Some(default.to_string()),
+            (None, None) => {
+                self.error(field, format!("environment variable {} is not set", name));
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(name: &str) -> Option<String> {
+        match name {
+            "REGISTRY" => Some("registry.local".to_string()),
+            "EMPTY" => Some("".to_string()),
+            _ => None,
+        }
+    }
+
+    fn interpolate(toml: &str) -> Result<(Value, Redactor), Vec<ConfigValidationError>> {
+        let mut value = ::toml::from_str::<Value>(toml).expect("valid toml");
+        let redactor = Interpolator::interpolate("easyharun.toml", &mut value, &env)?;
+        Ok((value, redactor))
+    }
+
+    #[test]
+    fn interpolate_env_and_defaults() {
+        let (value, _) = interpolate(r#"
+            image = "${REGISTRY}/nginx:latest"
+            listen = "0.0.0.0:${PORT:-89}"
+            empty = "${EMPTY:-fallback}"
+            escaped = "$${REGISTRY} costs $5"
+        "#).expect("interpolated");
+
+        assert_eq!("registry.local/nginx:latest", value["image"].as_str().unwrap());
+        assert_eq!("0.0.0.0:89", value["listen"].as_str().unwrap());
+        assert_eq!("fallback", value["empty"].as_str().unwrap());
+        assert_eq!("${REGISTRY} costs $5", value["escaped"].as_str().unwrap());
+    }
+
+    #[test]
+    fn interpolate_reports_missing_variables_with_location() {
+        let errors = interpolate(r#"
+            [[container]]
+            image = "${MISSING}"
+            name = "${file:/does/not/exist}"
+        "#).expect_err("missing variables");
+
+        let fields = errors.iter().map(|e| e.field.as_str()).collect::<Vec<_>>();
+
+        assert_eq!(vec!["container[0].image", "container[0].name"], fields);
+        assert!(errors[0].message.contains("MISSING"));
+    }
+
+    #[test]
+    fn interpolate_file_secrets_are_redacted() {
+        let secret_file = ::std::env::temp_dir().join(format!("easyharun_secret_test_{}", ::std::process::id()));
+        ::std::fs::write(&secret_file, "hunter2\n").expect("write secret");
+
+        let (value, redactor) = interpolate(&format!(r#"
+            password = "${{file:{}}}"
+        "#, secret_file.to_string_lossy())).expect("interpolated");
+
+        ::std::fs::remove_file(&secret_file).expect("cleanup");
+
+        assert_eq!("hunter2", value["password"].as_str().unwrap());
+        assert_eq!("PASSWORD=***", redactor.redact("PASSWORD=hunter2"));
+        assert!(!format!("{:?}", redactor).contains("hunter2"));
+    }
+}
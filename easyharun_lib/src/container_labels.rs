@@ -0,0 +1,214 @@
+use std::collections::HashMap;
+use anyhow::{anyhow, Context};
+use crate::config::{ConfigContainerProxy, ConfigContainerRuntime, DEFAULT_STOP_GRACE_PERIOD_S};
+
+// marks the containers easyharun manages, the value is the version of the label schema.
+pub const LABEL_MANAGED: &str = "easyharun";
+
+// bump it on every change of the labels older versions can not read, and keep a reader for the old version in ContainerLabels::from_labels.
+pub const LABEL_SCHEMA_VERSION: u32 = 2;
+
+// the instance of easyharun the container belongs to, stamped by the runtime.
+// containers without it belong to the default namespace.
+pub const LABEL_NAMESPACE: &str = "easyharun_namespace";
+
+pub const DEFAULT_NAMESPACE: &str = "default";
+
+// versions before the schema was versioned wrote the crate version.
+const LABEL_SCHEMA_V1: &str = "1.0.0";
+
+// what easyharun stores on a container, enough to rebuild it without the config.
+#[derive(Debug, Clone, Default)]
+pub struct ContainerLabels {
+    pub name: String,
+    pub image: String,
+    pub replica_id: u32,
+    pub container_ports: Vec<u32>,
+    pub health_checks: Vec<String>,
+    pub proxies: Vec<ConfigContainerProxy>,
+    pub stop_grace_period_s: u32,
+    // env values are never stored, they can be secrets and labels show up in every docker inspect.
+    // read from labels, env only has its keys, every value is empty. easyharun_spec_hash detects changes of the values.
+    pub runtime: ConfigContainerRuntime,
+    // only missing on containers of schema version 1.
+    pub spec_hash: Option<String>,
+    pub revision: Option<String>,
+}
+
+// None for containers easyharun does not manage.
+pub fn label_schema_version(labels: &HashMap<String, String>) -> Option<u32> {
+    match labels.get(LABEL_MANAGED)?.as_str() {
+        LABEL_SCHEMA_V1 => Some(1),
+        version => version.parse::<u32>().ok(),
+    }
+}
+
+pub fn is_managed_by_easyharun(labels: &HashMap<String, String>) -> bool {
+    label_schema_version(labels).is_some()
+}
+
+pub fn is_in_namespace(labels: &HashMap<String, String>, namespace: &str) -> bool {
+    labels.get(LABEL_NAMESPACE).map(|s| s.as_str()).unwrap_or(DEFAULT_NAMESPACE) == namespace
+}
+
+fn get_label<'a>(labels: &'a HashMap<String, String>, label: &str) -> Result<&'a String, ::anyhow::Error> {
+    labels.get(label).ok_or_else(|| anyhow!("container without {}", label))
+}
+
+fn parse_json_label<T: ::serde::de::DeserializeOwned>(labels: &HashMap<String, String>, label: &str) -> Result<T, ::anyhow::Error> {
+    serde_json::from_str(get_label(labels, label)?).context(format!("could not parse {} entry", label))
+}
+
+impl ContainerLabels {
+    pub fn to_labels(&self) -> HashMap<String, String> {
+        let mut labels = HashMap::new();
+
+        labels.insert(LABEL_MANAGED.to_string(), LABEL_SCHEMA_VERSION.to_string());
+        labels.insert("easyharun_name".to_string(), self.name.to_string());
+        labels.insert("easyharun_image".to_string(), self.image.to_string());
+        labels.insert("easyharun_replica_id".to_string(), self.replica_id.to_string());
+        // lists are json since version 2, podman drops the empty value of an empty comma list.
+        labels.insert("easyharun_container_ports".to_string(), serde_json::to_string(&self.container_ports).expect("ports are serializable"));
+        labels.insert("easyharun_health_checks".to_string(), serde_json::to_string(&self.health_checks).expect("health checks are serializable"));
+        labels.insert("easyharun_proxies".to_string(), serde_json::to_string(&self.proxies).expect("proxies are serializable"));
+        labels.insert("easyharun_stop_grace_period_s".to_string(), self.stop_grace_period_s.to_string());
+        labels.insert("easyharun_runtime".to_string(), serde_json::to_string(&self.runtime.without_env_values()).expect("runtime is serializable"));
+
+        if let Some(spec_hash) = &self.spec_hash {
+            labels.insert("easyharun_spec_hash".to_string(), spec_hash.to_string());
+        }
+
+        if let Some(revision) = &self.revision {
+            labels.insert("easyharun_revision".to_string(), revision.to_string());
+        }
+
+        labels
+    }
+
+    // reads every schema version up to LABEL_SCHEMA_VERSION.
+    pub fn from_labels(labels: &HashMap<String, String>) -> Result<Self, ::anyhow::Error> {
+        match label_schema_version(labels) {
+            None => Err(anyhow!("container is not managed by easyharun")),
+            Some(1) => Self::from_labels_v1(labels),
+            Some(LABEL_SCHEMA_VERSION) => Self::from_labels_v2(labels),
+            Some(version) => Err(anyhow!("unknown label schema version {}, this easyharun only reads up to {}", version, LABEL_SCHEMA_VERSION)),
+        }
+    }
+
+    fn from_labels_v2(labels: &HashMap<String, String>) -> Result<Self, ::anyhow::Error> {
+        Ok(Self {
+            name: get_label(labels, "easyharun_name")?.to_string(),
+            image: get_label(labels, "easyharun_image")?.to_string(),
+            replica_id: get_label(labels, "easyharun_replica_id")?.parse::<u32>().context("invalid easyharun_replica_id (not a number)")?,
+            container_ports: parse_json_label(labels, "easyharun_container_ports")?,
+            health_checks: parse_json_label(labels, "easyharun_health_checks")?,
+            proxies: parse_json_label(labels, "easyharun_proxies")?,
+            stop_grace_period_s: get_label(labels, "easyharun_stop_grace_period_s")?.parse::<u32>().context("invalid easyharun_stop_grace_period_s (not a number)")?,
+            runtime: parse_json_label(labels, "easyharun_runtime")?,
+            spec_hash: Some(get_label(labels, "easyharun_spec_hash")?.to_string()),
+            revision: Some(get_label(labels, "easyharun_revision")?.to_string()),
+        })
+    }
+
+    // comma lists, and the labels added later are optional.
+    fn from_labels_v1(labels: &HashMap<String, String>) -> Result<Self, ::anyhow::Error> {
+        let container_ports = get_label(labels, "easyharun_container_ports")?
+            .split(',')
+            .map(|v| v.trim().parse::<u32>().context("invalid easyharun_container_port (not a number)"))
+            .collect::<Result<Vec<u32>, ::anyhow::Error>>()?;
+
+        // podman drops labels with an empty value.
+        let health_checks = labels.get("easyharun_health_checks")
+            .map(|s| s.split(',').filter(|x| !x.is_empty()).map(|x| x.to_string()).collect())
+            .unwrap_or_default();
+
+        let stop_grace_period_s = match labels.get("easyharun_stop_grace_period_s") {
+            Some(s) => s.parse::<u32>().context("invalid easyharun_stop_grace_period_s (not a number)")?,
+            None => DEFAULT_STOP_GRACE_PERIOD_S,
+        };
+
+        let runtime = match labels.get("easyharun_runtime") {
+            Some(_) => parse_json_label(labels, "easyharun_runtime")?,
+            None => ConfigContainerRuntime::default(),
+        };
+
+        Ok(Self {
+            name: get_label(labels, "easyharun_name")?.to_string(),
+            image: get_label(labels, "easyharun_image")?.to_string(),
+            replica_id: get_label(labels, "easyharun_replica_id")?.parse::<u32>().context("invalid easyharun_replica_id (not a number)")?,
+            container_ports,
+            health_checks,
+            proxies: parse_json_label(labels, "easyharun_proxies")?,
+            stop_grace_period_s,
+            runtime,
+            spec_hash: labels.get("easyharun_spec_hash").cloned(),
+            revision: labels.get("easyharun_revision").cloned(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(labels: &[(&str, &str)]) -> HashMap<String, String> {
+        labels.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn env_values_are_not_stored_in_labels() {
+        let mut runtime = ConfigContainerRuntime::default();
+        runtime.env.insert("DB_PASSWORD".to_string(), "s3cr3t-value".to_string());
+
+        let labels = ContainerLabels {
+            name: "web".to_string(),
+            runtime,
+            spec_hash: Some("spec".to_string()),
+            revision: Some("revision".to_string()),
+            ..Default::default()
+        }.to_labels();
+
+        assert!(labels.values().all(|v| !v.contains("s3cr3t-value")));
+
+        let container = ContainerLabels::from_labels(&labels).expect("v2");
+        assert_eq!(Some(&String::new()), container.runtime.env.get("DB_PASSWORD"));
+    }
+
+    #[test]
+    fn read_all_label_schema_versions() {
+        let v1 = labels(&[
+            ("easyharun", "1.0.0"),
+            ("easyharun_name", "web"),
+            ("easyharun_image", "web:1"),
+            ("easyharun_replica_id", "2"),
+            ("easyharun_container_ports", "80, 443"),
+            ("easyharun_proxies", r#"[{"container_port": 80, "name": "http"}]"#),
+        ]);
+
+        let container = ContainerLabels::from_labels(&v1).expect("v1");
+        assert_eq!(vec![80, 443], container.container_ports);
+        assert!(container.health_checks.is_empty());
+        assert_eq!(DEFAULT_STOP_GRACE_PERIOD_S, container.stop_grace_period_s);
+        assert_eq!(None, container.spec_hash);
+
+        let v2 = ContainerLabels {
+            health_checks: vec!["http".to_string()],
+            spec_hash: Some("spec".to_string()),
+            revision: Some("revision".to_string()),
+            ..container
+        }.to_labels();
+
+        assert_eq!(Some(LABEL_SCHEMA_VERSION), label_schema_version(&v2));
+        let container = ContainerLabels::from_labels(&v2).expect("v2");
+        assert_eq!(("web", 2, vec![80, 443]), (container.name.as_str(), container.replica_id, container.container_ports));
+        assert_eq!(vec!["http".to_string()], container.health_checks);
+        assert_eq!("http", container.proxies[0].name);
+
+        assert!(ContainerLabels::from_labels(&labels(&[("easyharun", "99")])).is_err());
+        assert!(!is_managed_by_easyharun(&labels(&[("maintainer", "someone")])));
+
+        assert!(is_in_namespace(&v1, DEFAULT_NAMESPACE));
+        assert!(!is_in_namespace(&v1, "staging"));
+        assert!(is_in_namespace(&labels(&[(LABEL_NAMESPACE, "staging")]), "staging"));
+    }
+}
@@ -1,7 +1,14 @@
 pub mod config;
+pub mod config_interpolation;
+pub mod container_labels;
 pub mod portmapping;
 
-#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+use serde::{Deserialize, Serialize};
+use crate::container_labels::ContainerLabels;
+
+// the id the container runtime gave the container, not its name.
+// everything easyharun keeps about a running container is keyed by it.
+#[derive(Clone, Debug, Hash, Eq, PartialEq, Serialize, Deserialize)]
 pub struct ContainerId {
     id: String,
 }
@@ -16,4 +23,29 @@ impl ContainerId {
     pub fn as_str(&self) -> &str {
         self.id.as_str()
     }
-}
\ No newline at end of file
+}
+
+// who a container is, read from the runtime and the labels easyharun started it with.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ContainerIdentity {
+    pub id: ContainerId,
+    // the runtime name, without docker's leading slash.
+    pub name: String,
+    // the configured container name, shared by all replicas.
+    pub group: String,
+    pub replica_id: u32,
+    // containers created by older versions do not have a revision.
+    pub revision: Option<String>,
+}
+
+impl ContainerIdentity {
+    pub fn new(id: &str, name: &str, labels: &ContainerLabels) -> Self {
+        Self {
+            id: ContainerId::new(id.to_string()),
+            name: name.trim_start_matches('/').to_string(),
+            group: labels.name.to_string(),
+            replica_id: labels.replica_id,
+            revision: labels.revision.clone(),
+        }
+    }
+}
@@ -1,11 +1,17 @@
 #[cfg(test)]
 mod integration_test {
+    use std::sync::Arc;
     use std::time::Duration;
     use easyharun_lib::config::{Config, ConfigContainer, ConfigContainerProxy, ConfigFileHealthCheck, ConfigFileProxy};
     use easyharun_test_container::proto::{GetStatusRequest, KillServerRequest, MakeHealthcheckFailRequest};
     use easyharun_test_container::TestClient;
     use crate::config::config_provider::ConfigProvider;
     use crate::Core;
+    use crate::docker::docker_action_executer::DEFAULT_MAX_PARALLEL_ACTIONS;
+    use crate::container_manager::gc::GcOptions;
+    use crate::docker::docker_connection::RuntimeEndpoint;
+    use crate::docker::docker_runtime::DockerRuntime;
+    use crate::kv_container::KV;
 
     #[tokio::test(flavor = "multi_thread", worker_threads = 5)]
     async fn it_works() {
@@ -45,13 +51,19 @@ mod integration_test {
                             container_port: 5345,
                             name: "foo_grpc".to_string(),
                         }
-                    ]
+                    ],
+                    stop_grace_period_s: 10,
+                    ..ConfigContainer::default()
                 }
             ],
             ..Config::default()
         });
 
-        let (_, core) = Core::spawn(config_reader, true);
+        // EASYHARUN_RUNTIME_ENDPOINT=podman runs the test against podman.
+        let endpoint = ::std::env::var("EASYHARUN_RUNTIME_ENDPOINT").unwrap_or_default().parse::<RuntimeEndpoint>().expect("runtime endpoint");
+        let runtime = DockerRuntime::connect(&endpoint).expect("container runtime");
+
+        let (_, core) = Core::spawn(config_reader, Arc::new(runtime), KV::new(), DEFAULT_MAX_PARALLEL_ACTIONS, GcOptions::default(), true);
 
         ::tokio::time::sleep(Duration::from_secs(1)).await;
 
@@ -0,0 +1,40 @@
+use tonic::transport::Server;
+use tower_http::cors::{Any, CorsLayer};
+use easyact::ActorRegistry;
+use easyact::proto::proto_actor::actor_service_server::ActorServiceServer;
+use easyact::proto::service_actor::GrpcServiceActor;
+use crate::config::config_provider::ConfigReader;
+use crate::kv_container::KV;
+
+use self::{proto_admin::admin_service_server::AdminServiceServer, service_admin::GrpcServiceAdmin};
+
+pub mod proto_admin {
+    tonic::include_proto!("proto_admin");
+}
+
+pub mod service_admin;
+
+// serves the actor service of easyact and the easyharun admin service on the same address.
+pub async fn admin_run_grpc_server<S : AsRef<str>>(
+    addr: S,
+    actor_registry: ActorRegistry,
+    config_reader: ConfigReader,
+    kv: KV,
+) -> Result<(), ::anyhow::Error> {
+    let addr = addr.as_ref().parse()?;
+
+    let cors = CorsLayer::new()
+        .allow_origin(Any)
+        .allow_methods(Any)
+        .allow_headers(Any);
+
+    Server::builder()
+        .accept_http1(true)
+        .layer(cors)
+        .add_service(::tonic_web::enable(ActorServiceServer::new(GrpcServiceActor::new(actor_registry))))
+        .add_service(::tonic_web::enable(AdminServiceServer::new(GrpcServiceAdmin::new(config_reader, kv))))
+        .serve(addr)
+        .await?;
+
+    Ok(())
+}
@@ -0,0 +1,136 @@
+use std::time::{Instant, UNIX_EPOCH};
+use tonic::{Request, Status, Response};
+
+use crate::admin::proto_admin::{ConfigStatusGetRequest, ConfigStatusGetResponse, ConfigStatusGetResponseApplied, ContainerCanaryDecisionRequest, ContainerCanaryDecisionResponse, ContainerCanaryStatusGetRequest, ContainerCanaryStatusGetResponse, ContainerCanaryStatusGetResponseCanary, ContainerStatusGetRequest, ContainerStatusGetResponse, ContainerStatusGetResponseCrashLoop, ContainerStatusGetResponseGroup, ContainerStatusGetResponseReplacement};
+use crate::admin::proto_admin::admin_service_server::AdminService;
+use crate::config::config_provider::ConfigReader;
+use crate::kv_container::state::{CanaryDecision, ContainerGroupState};
+use crate::kv_container::KV;
+
+#[derive(Debug)]
+pub struct GrpcServiceAdmin {
+    config_reader: ConfigReader,
+    kv: KV,
+}
+
+impl GrpcServiceAdmin {
+    pub fn new(config_reader: ConfigReader, kv: KV) -> Self {
+        Self {
+            config_reader,
+            kv
+        }
+    }
+
+    async fn decide_canary(&self, name: &str, decision: CanaryDecision) -> Result<Response<ContainerCanaryDecisionResponse>, Status> {
+        let canary = match self.kv.get_canary_status().await.into_iter().find(|c| c.name == name) {
+            Some(s) => s,
+            None => return Err(Status::not_found(format!("container {} has no canary", name))),
+        };
+
+        self.kv.set_canary_decision(&canary.revision, decision).await;
+
+        Ok(Response::new(ContainerCanaryDecisionResponse {
+            revision: canary.revision,
+        }))
+    }
+}
+
+#[tonic::async_trait]
+impl AdminService for GrpcServiceAdmin {
+    async fn config_status_get(
+        &self,
+        _request: Request<ConfigStatusGetRequest>,
+    ) -> Result<Response<ConfigStatusGetResponse>, Status> {
+
+        let mut applied = self.config_reader.get_applied_generations().await.into_iter().map(|(manager, generation)| ConfigStatusGetResponseApplied {
+            manager,
+            generation,
+        }).collect::<Vec<_>>();
+        applied.sort_by(|a, b| a.manager.cmp(&b.manager));
+
+        Ok(Response::new(ConfigStatusGetResponse {
+            reload_error: self.config_reader.redact(&self.config_reader.get_reload_error().await.unwrap_or_default()),
+            generation: self.config_reader.get_generation(),
+            applied,
+        }))
+    }
+
+    async fn container_status_get(
+        &self,
+        _request: Request<ContainerStatusGetRequest>,
+    ) -> Result<Response<ContainerStatusGetResponse>, Status> {
+
+        let groups = self.kv.get_container_group_status().await.into_iter().map(|group| ContainerStatusGetResponseGroup {
+            name: group.name,
+            state: match group.state {
+                ContainerGroupState::RollingOut => "rolling_out",
+                ContainerGroupState::Healthy => "healthy",
+                ContainerGroupState::RolledBack => "rolled_back",
+            }.to_string(),
+            revision: group.revision,
+            healthy_revision: group.healthy_revision.unwrap_or_default(),
+            failed_revisions: group.failed_revisions,
+        }).collect();
+
+        let replacements = self.kv.get_container_replacements().await.into_iter().map(|replacement| ContainerStatusGetResponseReplacement {
+            name: replacement.name,
+            replica_id: replacement.replica_id,
+            container_id: replacement.container_id,
+            reason: replacement.reason,
+            replaced_at: replacement.replaced_at.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default(),
+        }).collect();
+
+        let now = Instant::now();
+        let mut crash_loops = self.kv.get_crash_loops().await.into_values().map(|crash_loop| ContainerStatusGetResponseCrashLoop {
+            crash_looping: crash_loop.is_crash_looping(),
+            name: crash_loop.name,
+            replica_id: crash_loop.replica_id,
+            restarts: crash_loop.restarts,
+            exit_code: crash_loop.last_exit.exit_code,
+            logs: crash_loop.last_exit.logs.iter().map(|l| self.config_reader.redact(l)).collect(),
+            backoff_remaining_s: crash_loop.backoff_until.saturating_duration_since(now).as_secs(),
+        }).collect::<Vec<_>>();
+        crash_loops.sort_by(|a, b| (&a.name, a.replica_id).cmp(&(&b.name, b.replica_id)));
+
+        Ok(Response::new(ContainerStatusGetResponse {
+            groups,
+            replacements,
+            crash_loops,
+        }))
+    }
+
+    async fn container_canary_status_get(
+        &self,
+        _request: Request<ContainerCanaryStatusGetRequest>,
+    ) -> Result<Response<ContainerCanaryStatusGetResponse>, Status> {
+
+        let canaries = self.kv.get_canary_status().await.into_iter().map(|canary| ContainerCanaryStatusGetResponseCanary {
+            name: canary.name,
+            revision: canary.revision,
+            weight: canary.weight,
+            replicas: canary.replicas,
+            healthy_replicas: canary.healthy_replicas,
+            connections: canary.connections,
+            connection_errors: canary.connection_errors,
+        }).collect();
+
+        Ok(Response::new(ContainerCanaryStatusGetResponse {
+            canaries,
+        }))
+    }
+
+    // promoting or aborting over the api lasts until the canary in the config changes.
+    async fn container_canary_promote(
+        &self,
+        request: Request<ContainerCanaryDecisionRequest>,
+    ) -> Result<Response<ContainerCanaryDecisionResponse>, Status> {
+        self.decide_canary(&request.into_inner().name, CanaryDecision::Promote).await
+    }
+
+    async fn container_canary_abort(
+        &self,
+        request: Request<ContainerCanaryDecisionRequest>,
+    ) -> Result<Response<ContainerCanaryDecisionResponse>, Status> {
+        self.decide_canary(&request.into_inner().name, CanaryDecision::Abort).await
+    }
+}
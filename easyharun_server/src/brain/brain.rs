@@ -1,41 +1,326 @@
+use std::collections::{BTreeSet, HashMap};
+use easyharun_lib::config::{ConfigContainerUpdate, ConfigContainerUpdateStrategy};
 use crate::brain::brain_action::{BrainAction, ContainerStart, ContainerStop};
-use crate::container_manager::world::{WorldDiff, Worlds};
+use crate::container_manager::world::{WorldContainer, WorldDiff, Worlds};
 
 pub struct Brain {}
 
+// all containers of a [[container]] entry, they are rolled out together.
+struct ContainerGroup<'a> {
+    name: &'a str,
+    replicas: usize,
+    update: ConfigContainerUpdate,
+    current: Vec<&'a WorldContainer>,
+    to_start: Vec<&'a WorldContainer>,
+    to_stop: Vec<&'a WorldContainer>,
+}
+
+impl<'a> ContainerGroup<'a> {
+    fn new(name: &'a str, worlds: &'a Worlds, world_diff: &'a WorldDiff) -> Self {
+        let expected = worlds.expected.get_containers().iter().filter(|c| c.name == name).collect::<Vec<_>>();
+
+        Self {
+            name,
+            replicas: expected.len(),
+            update: expected.first().map(|c| c.update.clone()).unwrap_or_default(),
+            current: worlds.current.get_containers().iter().filter(|c| c.name == name).collect(),
+            to_start: world_diff.containers_does_not_exists_but_should_exists().iter().filter(|c| c.name == name).collect(),
+            to_stop: world_diff.containers_exists_but_should_not_exists().iter().filter(|c| c.name == name).collect(),
+        }
+    }
+
+    fn ready(&self) -> usize {
+        self.current.iter().filter(|c| c.ready).count()
+    }
+}
+
 impl Brain {
-    pub fn think_about_next_action(worlds : &Worlds) -> Result<BrainAction, ::anyhow::Error> {
+    // everything that can be done right now, starts and stops in the plan are independent of each other.
+    // an empty plan means there is nothing left to do.
+    pub fn think_about_next_actions(worlds : &Worlds) -> Result<Vec<BrainAction>, ::anyhow::Error> {
 
         let world_diff = worlds.build_diff_world();
 
-        match Self::think_about_starting_new_containers(&world_diff)? {
-            Some(s) => return Ok(s),
-            None => {},
+        let names = world_diff.containers_does_not_exists_but_should_exists().iter()
+            .chain(world_diff.containers_exists_but_should_not_exists().iter())
+            .map(|c| c.name.as_str())
+            .collect::<BTreeSet<_>>();
+
+        let mut actions = vec![];
+        let mut waiting = vec![];
+
+        for name in names {
+            let group = ContainerGroup::new(name, worlds, &world_diff);
+
+            let group_actions = match group.update.strategy {
+                ConfigContainerUpdateStrategy::Rolling => {
+                    let mut buf = Self::think_about_starting_new_containers(&group)?;
+                    buf.extend(Self::think_about_stopping_existing_containers(&group)?);
+                    buf
+                },
+                ConfigContainerUpdateStrategy::BlueGreen => Self::think_about_blue_green(&group)?,
+            };
+
+            if group_actions.is_empty() {
+                waiting.push(format!("{} ({} of {} replicas ready)", group.name, group.ready(), group.replicas));
+            }
+
+            actions.extend(group_actions);
         }
 
-        match Self::think_about_stopping_existing_containers(&world_diff)? {
-            Some(s) => return Ok(s),
-            None => {},
+        if !waiting.is_empty() {
+            actions.push(BrainAction::Wait(format!("waiting for replicas to become ready: {}", waiting.join(", "))));
         }
 
-        Ok(BrainAction::NoOp)
+        Ok(actions)
     }
 
-    fn think_about_starting_new_containers(world_diff : &WorldDiff) -> Result<Option<BrainAction>, ::anyhow::Error> {
-        match world_diff.containers_does_not_exists_but_should_exists().first() {
-            None => Ok(None),
-            Some(s) => Ok(Some(BrainAction::ContainersStart(vec![
-                ContainerStart::new_from_world_container(s)
-            ])))
+    // new replicas are started as long as the group stays within replicas + max_surge.
+    fn think_about_starting_new_containers(group : &ContainerGroup) -> Result<Vec<BrainAction>, ::anyhow::Error> {
+        let capacity = (group.replicas + group.update.max_surge as usize).saturating_sub(group.current.len());
+
+        let starts = group.to_start.iter()
+            .take(capacity)
+            .map(|c| ContainerStart::new_from_world_container(c))
+            .collect::<Vec<_>>();
+
+        match starts.is_empty() {
+            true => Ok(vec![]),
+            false => Ok(vec![BrainAction::ContainersStart(starts)]),
+        }
+    }
+
+    // old replicas are stopped as long as at least replicas - max_unavailable stay ready.
+    fn think_about_stopping_existing_containers(group : &ContainerGroup) -> Result<Vec<BrainAction>, ::anyhow::Error> {
+        let min_ready = group.replicas.saturating_sub(group.update.max_unavailable as usize);
+        let mut ready = group.ready();
+
+        // stopping a replica that is not ready does not hurt.
+        let mut candidates = group.to_stop.clone();
+        candidates.sort_by_key(|c| c.ready);
+
+        let mut stops = vec![];
+
+        for container in candidates {
+            if container.ready {
+                if ready <= min_ready {
+                    break;
+                }
+                ready -= 1;
+            }
+
+            stops.push(ContainerStop::new_from_world_container(container)?);
+        }
+
+        match stops.is_empty() {
+            true => Ok(vec![]),
+            false => Ok(vec![BrainAction::ContainersStop(stops)]),
         }
     }
 
-    fn think_about_stopping_existing_containers(world_diff : &WorldDiff) -> Result<Option<BrainAction>, ::anyhow::Error> {
-        match world_diff.containers_exists_but_should_not_exists().first() {
-            None => Ok(None),
-            Some(s) => Ok(Some(BrainAction::ContainersStop(vec![
-                ContainerStop::new_from_world_container(s)?
-            ])))
+    // green (the new replicas) is started completely, blue goes at once when all of green serves traffic.
+    fn think_about_blue_green(group : &ContainerGroup) -> Result<Vec<BrainAction>, ::anyhow::Error> {
+        if !group.to_start.is_empty() {
+            return Ok(vec![BrainAction::ContainersStart(
+                group.to_start.iter().map(|c| ContainerStart::new_from_world_container(c)).collect()
+            )]);
+        }
+
+        if group.to_stop.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let green = group.current.iter()
+            .filter(|c| !group.to_stop.iter().any(|s| s.container_id == c.container_id))
+            .collect::<Vec<_>>();
+
+        if green.len() < group.replicas || green.iter().any(|c| !c.ready) {
+            return Ok(vec![]);
         }
+
+        Ok(vec![BrainAction::ContainersStop(
+            group.to_stop.iter().map(|c| ContainerStop::new_from_world_container(c)).collect::<Result<Vec<_>, _>>()?
+        )])
+    }
+
+    // the proxies of a blue/green group route to a single revision only.
+    // they switch to the expected revision once all of its replicas are healthy.
+    pub fn think_about_live_revisions(worlds : &Worlds, previous : &HashMap<String, String>) -> HashMap<String, String> {
+        let mut live_revisions = HashMap::new();
+
+        let names = worlds.expected.get_containers().iter()
+            .filter(|c| c.update.strategy == ConfigContainerUpdateStrategy::BlueGreen)
+            .map(|c| c.name.as_str())
+            .collect::<BTreeSet<_>>();
+
+        for name in names {
+            let expected = worlds.expected.get_containers().iter().filter(|c| c.name == name).collect::<Vec<_>>();
+            let current = worlds.current.get_containers().iter().filter(|c| c.name == name).collect::<Vec<_>>();
+
+            let revision = expected[0].compute_revision();
+
+            let green_healthy = expected.iter().all(|e| {
+                let spec_hash = e.compute_spec_hash();
+                current.iter().any(|c| c.healthy && c.get_identifier() == e.get_identifier() && c.has_spec_hash(&spec_hash))
+            });
+
+            let blue = previous.get(name)
+                .filter(|r| current.iter().any(|c| c.revision.as_ref() == Some(*r)))
+                .cloned()
+                .or(current.iter().filter_map(|c| c.revision.clone()).filter(|r| r != &revision).min());
+
+            match (green_healthy, blue) {
+                (true, _) => live_revisions.insert(name.to_string(), revision),
+                (false, Some(blue)) => live_revisions.insert(name.to_string(), blue),
+                // nothing else runs, so there is nothing to hold back.
+                (false, None) => None,
+            };
+        }
+
+        live_revisions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use easyharun_lib::ContainerId;
+    use crate::container_manager::world::World;
+    use super::*;
+
+    fn container(image: &str, replica_id: u32, ready: bool) -> WorldContainer {
+        WorldContainer {
+            container_id: Some(ContainerId::new(format!("{}-{}", image, replica_id))),
+            name: "web".to_string(),
+            image: image.to_string(),
+            replica_id,
+            container_ports: vec![80],
+            ready,
+            ..Default::default()
+        }
+    }
+
+    fn single_action(worlds: &Worlds) -> BrainAction {
+        let mut actions = Brain::think_about_next_actions(worlds).expect("actions");
+        assert_eq!(1, actions.len(), "{:?}", actions);
+        actions.remove(0)
+    }
+
+    #[test]
+    fn all_missing_replicas_are_started_at_once() {
+        let worlds = Worlds {
+            expected: World::new((0..20).map(|replica_id| container("web:1", replica_id, false)).collect()),
+            current: World::new(vec![]),
+        };
+
+        match single_action(&worlds) {
+            BrainAction::ContainersStart(s) => assert_eq!(20, s.len()),
+            a => panic!("unexpected action {:?}", a),
+        };
+    }
+
+    #[test]
+    fn rolling_update_waits_for_the_new_replica_to_be_ready() {
+        let expected = vec![container("web:2", 0, false), container("web:2", 1, false)];
+
+        // max_surge = 1, so only one new replica is started on top of the old ones.
+        let worlds = Worlds {
+            expected: World::new(expected.clone()),
+            current: World::new(vec![container("web:1", 0, true), container("web:1", 1, true)]),
+        };
+
+        match single_action(&worlds) {
+            BrainAction::ContainersStart(s) => assert_eq!("web:2", s[0].container_world.image),
+            a => panic!("unexpected action {:?}", a),
+        };
+
+        let worlds = Worlds {
+            expected: World::new(expected.clone()),
+            current: World::new(vec![container("web:1", 0, true), container("web:1", 1, true), container("web:2", 0, false)]),
+        };
+
+        match single_action(&worlds) {
+            BrainAction::Wait(_) => {},
+            a => panic!("unexpected action {:?}", a),
+        };
+
+        let worlds = Worlds {
+            expected: World::new(expected),
+            current: World::new(vec![container("web:1", 0, true), container("web:1", 1, true), container("web:2", 0, true)]),
+        };
+
+        match single_action(&worlds) {
+            BrainAction::ContainersStop(s) => assert_eq!("web:1", s[0].world_container.image),
+            a => panic!("unexpected action {:?}", a),
+        };
+    }
+
+    #[test]
+    fn rolling_update_with_max_unavailable_stops_first() {
+        let mut expected = container("web:2", 0, false);
+        expected.update = ConfigContainerUpdate { max_surge: 0, max_unavailable: 1, ..Default::default() };
+
+        let worlds = Worlds {
+            expected: World::new(vec![expected]),
+            current: World::new(vec![container("web:1", 0, true)]),
+        };
+
+        match single_action(&worlds) {
+            BrainAction::ContainersStop(s) => assert_eq!("web:1", s[0].world_container.image),
+            a => panic!("unexpected action {:?}", a),
+        };
+    }
+
+    #[test]
+    fn blue_green_switches_once_green_is_healthy() {
+        let green = |replica_id, healthy| {
+            let mut c = container("web:2", replica_id, healthy);
+            c.update.strategy = ConfigContainerUpdateStrategy::BlueGreen;
+            c.spec_hash = Some(c.compute_spec_hash());
+            c.revision = Some(c.compute_revision());
+            c.healthy = healthy;
+            c
+        };
+
+        let blue = |replica_id| {
+            let mut c = container("web:1", replica_id, true);
+            c.revision = Some(c.compute_revision());
+            c.healthy = true;
+            c
+        };
+
+        let expected = World::new(vec![green(0, false), green(1, false)]);
+
+        // all of green is started before anything else happens.
+        let worlds = Worlds {
+            expected: expected.clone(),
+            current: World::new(vec![blue(0), blue(1), green(0, false)]),
+        };
+
+        match single_action(&worlds) {
+            BrainAction::ContainersStart(s) => assert_eq!(1, s[0].container_world.replica_id),
+            a => panic!("unexpected action {:?}", a),
+        };
+
+        let worlds = Worlds {
+            expected: expected.clone(),
+            current: World::new(vec![blue(0), blue(1), green(0, true), green(1, false)]),
+        };
+
+        let live_revisions = Brain::think_about_live_revisions(&worlds, &HashMap::new());
+        assert_eq!(blue(0).revision.as_ref(), live_revisions.get("web"));
+        assert!(matches!(single_action(&worlds), BrainAction::Wait(_)));
+
+        let worlds = Worlds {
+            expected,
+            current: World::new(vec![blue(0), blue(1), green(0, true), green(1, true)]),
+        };
+
+        let live_revisions = Brain::think_about_live_revisions(&worlds, &live_revisions);
+        assert_eq!(green(0, true).revision.as_ref(), live_revisions.get("web"));
+
+        match single_action(&worlds) {
+            BrainAction::ContainersStop(s) => assert_eq!(2, s.len()),
+            a => panic!("unexpected action {:?}", a),
+        };
     }
-}
\ No newline at end of file
+}
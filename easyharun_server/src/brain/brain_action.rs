@@ -6,7 +6,8 @@ use crate::container_manager::world::WorldContainer;
 pub enum BrainAction {
     ContainersStart(Vec<ContainerStart>),
     ContainersStop(Vec<ContainerStop>),
-    NoOp,
+    // something is left to do, but the brain waits for containers to become ready.
+    Wait(String),
 }
 
 #[derive(Debug, Clone)]
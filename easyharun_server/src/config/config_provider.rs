@@ -1,43 +1,150 @@
+use std::collections::HashMap;
 use std::sync::Arc;
-use tokio::sync::{RwLock};
+use anyhow::Context;
+use tokio::sync::{RwLock, watch};
+use tokio::task::JoinHandle;
+use easyact::ActorStateHandle;
 use easyharun_lib::config::Config;
 
 pub struct ConfigProvider;
 
+// every successful (re)load gets a new generation, starting with 1.
+#[derive(Clone, Debug)]
+pub struct ConfigSnapshot {
+    pub generation: u64,
+    pub config: Arc<Config>,
+}
+
+#[derive(Clone, Debug)]
+pub struct ConfigChanged {
+    pub generation: u64,
+}
+
+#[derive(Debug, Default)]
+struct ConfigStatus {
+    // the config stays untouched when a reload fails, the error is kept here.
+    reload_error: Option<String>,
+    // generation each manager applied last, by manager name.
+    applied_generations: HashMap<String, u64>,
+}
+
 impl ConfigProvider {
     pub fn new(config: Config) -> (ConfigReader, ConfigReaderWriter) {
-        let arc = Arc::new(RwLock::new(config));
+        let (sender, receiver) = watch::channel(ConfigSnapshot {
+            generation: 1,
+            config: Arc::new(config),
+        });
+
+        let status = Arc::new(RwLock::new(ConfigStatus::default()));
 
         (
-            ConfigReader {config: arc.clone()},
-            ConfigReaderWriter {config: arc},
+            ConfigReader {config: receiver, status: status.clone()},
+            ConfigReaderWriter {config: Arc::new(sender), status},
         )
     }
 }
 
 #[derive(Clone, Debug)]
 pub struct ConfigReader {
-    config: Arc<RwLock<Config>>,
+    config: watch::Receiver<ConfigSnapshot>,
+    status: Arc<RwLock<ConfigStatus>>,
 }
 
 #[derive(Clone, Debug)]
 pub struct ConfigReaderWriter {
-    config: Arc<RwLock<Config>>,
+    config: Arc<watch::Sender<ConfigSnapshot>>,
+    status: Arc<RwLock<ConfigStatus>>,
 }
 
 impl ConfigReader {
-    pub async fn get_copy(&self) -> Config {
-        self.config.read().await.clone()
+    pub fn get_snapshot(&self) -> ConfigSnapshot {
+        self.config.borrow().clone()
+    }
+
+    pub fn get_generation(&self) -> u64 {
+        self.config.borrow().generation
+    }
+
+    // hides secrets of the current config, use it for everything that is logged or exposed.
+    pub fn redact(&self, text: &str) -> String {
+        self.config.borrow().config.redactor.redact(text)
+    }
+
+    // waits until a config with a newer generation than the last one seen by this reader is set.
+    pub async fn changed(&mut self) -> Result<ConfigSnapshot, ::anyhow::Error> {
+        self.config.changed().await.context("config provider is gone")?;
+        Ok(self.config.borrow_and_update().clone())
+    }
+
+    // sends a message to the actor whenever the config changes.
+    pub fn subscribe<MSG, F>(&self, actor: ActorStateHandle<MSG>, to_msg: F) -> JoinHandle<()>
+        where MSG: Send + Sync + Unpin + 'static, F: Fn(ConfigChanged) -> MSG + Send + 'static
+    {
+        let mut config_reader = self.clone();
+
+        ::tokio::spawn(async move {
+            loop {
+                let snapshot = match config_reader.changed().await {
+                    Ok(s) => s,
+                    Err(_) => return,
+                };
+
+                if actor.send(to_msg(ConfigChanged { generation: snapshot.generation })).await.is_err() {
+                    return;
+                }
+            }
+        })
+    }
+
+    pub async fn get_reload_error(&self) -> Option<String> {
+        self.status.read().await.reload_error.clone()
+    }
+
+    pub async fn set_applied_generation(&self, manager: &str, generation: u64) {
+        self.status.write().await.applied_generations.insert(manager.to_string(), generation);
+    }
+
+    pub async fn get_applied_generations(&self) -> HashMap<String, u64> {
+        self.status.read().await.applied_generations.clone()
     }
 }
 
 impl ConfigReaderWriter {
     pub async fn get_copy(&self) -> Config {
-        self.config.read().await.clone()
+        self.config.borrow().config.as_ref().clone()
     }
 
     pub async fn set(&self, config : Config) {
-        let mut w = self.config.write().await;
-        *w = config;
+        self.status.write().await.reload_error = None;
+
+        self.config.send_modify(|snapshot| {
+            snapshot.generation += 1;
+            snapshot.config = Arc::new(config);
+        });
+    }
+
+    pub async fn set_reload_error(&self, reload_error : String) {
+        self.status.write().await.reload_error = Some(reload_error);
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn set_bumps_generation_and_notifies_readers() {
+        let (mut config_reader, config_writer) = ConfigProvider::new(Config::default());
+
+        assert_eq!(1, config_reader.get_generation());
+
+        config_writer.set_reload_error("broken".to_string()).await;
+        assert_eq!(1, config_reader.get_generation());
+        assert_eq!(Some("broken".to_string()), config_reader.get_reload_error().await);
+
+        config_writer.set(Config::default()).await;
+
+        assert_eq!(2, config_reader.changed().await.expect("changed").generation);
+        assert_eq!(None, config_reader.get_reload_error().await);
+    }
+}
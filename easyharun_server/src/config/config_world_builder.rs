@@ -1,10 +1,8 @@
 
-use crate::config::config_provider::{ConfigReader};
+use easyharun_lib::config::Config;
 use crate::container_manager::world::{World, WorldContainer};
 
-pub async fn build_world_from_config(config_reader : &ConfigReader) -> Result<World, ::anyhow::Error> {
-    let config = config_reader.get_copy().await;
-
+pub async fn build_world_from_config(config : &Config) -> Result<World, ::anyhow::Error> {
     let mut containers = vec![];
 
     for config_container in config.container.iter() {
@@ -16,7 +14,17 @@ pub async fn build_world_from_config(config_reader : &ConfigReader) -> Result<Wo
             replica_id: config_container.replica_id.clone(),
             container_ports: config_container.container_ports.clone(),
             proxies: config_container.proxies.clone(),
-            health_checks: config_container.health_checks.clone()
+            health_checks: config_container.health_checks.clone(),
+            stop_grace_period_s: config_container.stop_grace_period_s,
+            restart_after_failures: config_container.restart_after_failures,
+            unhealthy_grace_s: config_container.unhealthy_grace_s,
+            runtime: config_container.runtime.clone(),
+            update: config_container.update.clone(),
+            canary_weight: config_container.canary_weight,
+            spec_hash: None,
+            revision: None,
+            healthy: false,
+            ready: false,
         });
     }
 
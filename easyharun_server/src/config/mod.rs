@@ -9,14 +9,22 @@ use futures::{
     SinkExt, StreamExt,
 };
 use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
-use std::path::Path;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use anyhow::Context;
+use tracing::{info, warn};
 use easyharun_lib::config::Config;
 use crate::config::config_provider::ConfigReaderWriter;
 
+// editors write a file in several steps, we wait until the events calm down.
+const CONFIG_RELOAD_DEBOUNCE: Duration = Duration::from_millis(300);
+
 impl ConfigMonitor {
 
-    pub async fn load_config() -> Config {
-        crate::Config::read_from_file("./example/basic/easyharun.toml").await.expect("could not read config")
+    // the config path could be a file or a directory, every *.toml file inside a directory is part of the config.
+    pub async fn load_config(config_path: &str) -> Result<Config, ::anyhow::Error> {
+        crate::Config::read_from_file(config_path).await.context("could not read config")
     }
 
     fn async_watcher() -> notify::Result<(RecommendedWatcher, Receiver<notify::Result<Event>>)> {
@@ -33,22 +41,86 @@ impl ConfigMonitor {
         Ok((watcher, rx))
     }
 
-    pub async fn async_watch(config_writer : ConfigReaderWriter) -> notify::Result<()> {
+    // we watch directories, editors often replace a file instead of writing to it,
+    // a watch on the file itself would get lost. watching the directories also catches new files.
+    fn watch_dirs(sources: &[String]) -> HashSet<PathBuf> {
+        sources.iter().map(|source| {
+            let path = Path::new(source);
+
+            if path.is_dir() {
+                return path.to_path_buf();
+            }
+
+            match path.parent() {
+                Some(s) if s != Path::new("") => s.to_path_buf(),
+                _ => PathBuf::from("."),
+            }
+        }).collect()
+    }
+
+    fn update_watches(watcher: &mut RecommendedWatcher, watching: &mut HashSet<PathBuf>, sources: &[String]) {
+        let watch_dirs = Self::watch_dirs(sources);
+
+        for dir in watching.difference(&watch_dirs) {
+            if let Err(e) = watcher.unwatch(dir) {
+                warn!("could not unwatch {:?}: {:?}", dir, e);
+            }
+        }
+
+        for dir in watch_dirs.difference(watching) {
+            if let Err(e) = watcher.watch(dir, RecursiveMode::NonRecursive) {
+                warn!("could not watch {:?}: {:?}", dir, e);
+            }
+        }
+
+        *watching = watch_dirs;
+    }
+
+    pub async fn async_watch(config_path: String, config_writer : ConfigReaderWriter) -> notify::Result<()> {
         let (mut watcher, mut rx) = Self::async_watcher()?;
 
-        // Add a path to be watched. All files and directories at that path and
-        // below will be monitored for changes.
-        watcher.watch(Path::new("./example/basic/easyharun.toml"), RecursiveMode::Recursive)?;
+        let mut watching = HashSet::new();
+        Self::update_watches(&mut watcher, &mut watching, &config_writer.get_copy().await.sources);
 
         while let Some(res) = rx.next().await {
             match res {
-                Ok(event) => {
-                    config_writer.set(Self::load_config().await).await
+                Ok(event) if Self::is_config_event(&event) => {},
+                Ok(_) => continue,
+                Err(e) => {
+                    warn!("watch error: {:?}", e);
+                    continue;
                 },
-                Err(e) => println!("watch error: {:?}", e),
-            }
+            };
+
+            // swallow all events that arrive while the files are still being written.
+            while let Ok(Some(_)) = ::tokio::time::timeout(CONFIG_RELOAD_DEBOUNCE, rx.next()).await {}
+
+            let namespace = config_writer.get_copy().await.get_namespace().to_string();
+
+            match Self::load_config(&config_path).await {
+                Ok(config) if config.get_namespace() != namespace => {
+                    let e = format!("the namespace can not change from {} to {} while easyharun runs, restart it", namespace, config.get_namespace());
+                    warn!("could not reload config, keeping the last good config. error: {}", e);
+                    config_writer.set_reload_error(e).await;
+                },
+                Ok(config) => {
+                    info!("config reloaded");
+                    // includes could have changed.
+                    Self::update_watches(&mut watcher, &mut watching, &config.sources);
+                    config_writer.set(config).await;
+                },
+                // the error is redacted with the secrets of the failing config already.
+                Err(e) => {
+                    warn!("could not reload config, keeping the last good config. error: {:#}", e);
+                    config_writer.set_reload_error(format!("{:#}", e)).await;
+                }
+            };
         }
 
         Ok(())
     }
-}
\ No newline at end of file
+
+    fn is_config_event(event: &Event) -> bool {
+        event.paths.iter().any(|p| p.extension().map(|e| e == "toml").unwrap_or(false))
+    }
+}
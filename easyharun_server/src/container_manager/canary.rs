@@ -0,0 +1,108 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use crate::container_manager::world::{World, WorldContainer};
+use crate::kv_container::state::{CanaryDecision, CanaryStatus};
+use crate::kv_container::KV;
+
+// promote: the canary replaces the replicas of the container. abort: the canary replicas go away.
+pub fn apply_canary_decisions(expected: World, decisions: &HashMap<String, CanaryDecision>) -> World {
+    let mut promoted : HashMap<String, WorldContainer> = HashMap::new();
+    let mut containers = vec![];
+
+    for container in expected.get_containers() {
+        if container.canary_weight.is_none() {
+            containers.push(container.clone());
+            continue;
+        }
+
+        match decisions.get(&container.compute_revision()) {
+            None => containers.push(container.clone()),
+            Some(CanaryDecision::Abort) => {},
+            Some(CanaryDecision::Promote) => {
+                promoted.insert(container.name.clone(), container.clone());
+            },
+        };
+    }
+
+    World::new(containers.into_iter().map(|container| match promoted.get(&container.name) {
+        Some(canary) => WorldContainer {
+            replica_id: container.replica_id,
+            canary_weight: None,
+            ..canary.clone()
+        },
+        None => container,
+    }).collect())
+}
+
+// decisions for other revisions are stale, the config moved on.
+pub fn get_canary_revisions(expected: &World) -> HashSet<String> {
+    expected.get_containers().iter()
+        .filter(|c| c.canary_weight.is_some())
+        .map(|c| c.compute_revision())
+        .collect()
+}
+
+pub async fn build_canary_status(expected: &World, current: &World, kv: &KV) -> Vec<CanaryStatus> {
+    let mut canaries : BTreeMap<String, CanaryStatus> = BTreeMap::new();
+
+    for container in expected.get_containers() {
+        let weight = match container.canary_weight {
+            Some(s) => s,
+            None => continue,
+        };
+
+        let canary = canaries.entry(container.name.clone()).or_insert_with(|| CanaryStatus {
+            name: container.name.clone(),
+            revision: container.compute_revision(),
+            weight,
+            ..Default::default()
+        });
+
+        canary.replicas += 1;
+    }
+
+    for canary in canaries.values_mut() {
+        for container in current.get_containers().iter().filter(|c| c.revision.as_ref() == Some(&canary.revision)) {
+            if container.healthy {
+                canary.healthy_replicas += 1;
+            }
+
+            for server_addr in container.get_server_addrs() {
+                let stats = kv.get_proxy_connection_stats(&server_addr).await;
+                canary.connections += stats.connections;
+                canary.connection_errors += stats.errors;
+            }
+        }
+    }
+
+    canaries.into_values().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn container(image: &str, replica_id: u32, canary_weight: Option<u32>) -> WorldContainer {
+        WorldContainer {
+            name: "web".to_string(),
+            image: image.to_string(),
+            replica_id,
+            container_ports: vec![80],
+            canary_weight,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn promote_and_abort_canary() {
+        let expected = World::new(vec![container("web:1", 0, None), container("web:1", 1, None), container("web:2", 0, Some(10))]);
+        let canary_revision = container("web:2", 0, Some(10)).compute_revision();
+        assert_eq!(HashSet::from([canary_revision.clone()]), get_canary_revisions(&expected));
+
+        let aborted = apply_canary_decisions(expected.clone(), &HashMap::from([(canary_revision.clone(), CanaryDecision::Abort)]));
+        assert_eq!(vec!["web:1", "web:1"], aborted.get_containers().iter().map(|c| c.image.as_str()).collect::<Vec<_>>());
+
+        let promoted = apply_canary_decisions(expected, &HashMap::from([(canary_revision, CanaryDecision::Promote)]));
+        assert_eq!(vec!["web:2", "web:2"], promoted.get_containers().iter().map(|c| c.image.as_str()).collect::<Vec<_>>());
+        assert!(promoted.get_containers().iter().all(|c| c.canary_weight.is_none()));
+    }
+}
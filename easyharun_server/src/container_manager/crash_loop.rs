@@ -0,0 +1,90 @@
+use std::collections::HashMap;
+use std::time::Instant;
+use crate::brain::brain_action::BrainAction;
+use crate::kv_container::state::CrashLoop;
+
+// removes the starts of replicas that are still backing off, by container identifier.
+pub fn hold_back_crashed_starts(actions: Vec<BrainAction>, crash_loops: &HashMap<String, CrashLoop>, now: Instant) -> Vec<BrainAction> {
+    let mut held_back = vec![];
+    let mut buf = vec![];
+
+    for action in actions {
+        let starts = match action {
+            BrainAction::ContainersStart(s) => s,
+            action => {
+                buf.push(action);
+                continue;
+            }
+        };
+
+        let (starts, backing_off) : (Vec<_>, Vec<_>) = starts.into_iter().partition(|s| match crash_loops.get(&s.container_world.get_identifier()) {
+            Some(crash_loop) => crash_loop.backoff_until <= now,
+            None => true,
+        });
+
+        for start in backing_off {
+            let crash_loop = &crash_loops[&start.container_world.get_identifier()];
+            held_back.push(format!("{} replica {} ({} restarts, {}s left)", crash_loop.name, crash_loop.replica_id, crash_loop.restarts, crash_loop.backoff_until.duration_since(now).as_secs()));
+        }
+
+        if !starts.is_empty() {
+            buf.push(BrainAction::ContainersStart(starts));
+        }
+    }
+
+    if !held_back.is_empty() {
+        buf.push(BrainAction::Wait(format!("backing off crashed replicas: {}", held_back.join(", "))));
+    }
+
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use crate::brain::brain_action::ContainerStart;
+    use crate::kv_container::state::{crash_loop_backoff, ContainerExit};
+    use crate::container_manager::world::WorldContainer;
+    use super::*;
+
+    fn exit() -> ContainerExit {
+        ContainerExit { exit_code: Some(1), logs: vec!["boom".to_string()] }
+    }
+
+    #[test]
+    fn backoff_grows_up_to_the_cap() {
+        assert_eq!(Duration::from_secs(2), crash_loop_backoff(1));
+        assert_eq!(Duration::from_secs(4), crash_loop_backoff(2));
+        assert_eq!(Duration::from_secs(256), crash_loop_backoff(8));
+        assert_eq!(Duration::from_secs(300), crash_loop_backoff(9));
+        assert_eq!(Duration::from_secs(300), crash_loop_backoff(100));
+    }
+
+    #[test]
+    fn crashed_replicas_are_held_back() {
+        let now = Instant::now();
+        let crashed = WorldContainer { name: "web".to_string(), replica_id: 0, ..Default::default() };
+        let other = WorldContainer { name: "web".to_string(), replica_id: 1, ..Default::default() };
+
+        let mut crash_loop = CrashLoop::record_exit(None, "web", 0, exit(), now);
+        for _ in 0..2 {
+            crash_loop = CrashLoop::record_exit(Some(&crash_loop), "web", 0, exit(), now);
+        }
+        assert!(crash_loop.is_crash_looping());
+
+        let crash_loops = HashMap::from([(crashed.get_identifier(), crash_loop)]);
+        let actions = vec![BrainAction::ContainersStart(vec![ContainerStart::new_from_world_container(&crashed), ContainerStart::new_from_world_container(&other)])];
+
+        let held_back = hold_back_crashed_starts(actions.clone(), &crash_loops, now);
+        match &held_back[..] {
+            [BrainAction::ContainersStart(s), BrainAction::Wait(_)] => assert_eq!(1, s[0].container_world.replica_id),
+            a => panic!("unexpected actions {:?}", a),
+        };
+
+        let after_backoff = hold_back_crashed_starts(actions, &crash_loops, now + Duration::from_secs(8));
+        match &after_backoff[..] {
+            [BrainAction::ContainersStart(s)] => assert_eq!(2, s.len()),
+            a => panic!("unexpected actions {:?}", a),
+        };
+    }
+}
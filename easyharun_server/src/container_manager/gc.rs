@@ -0,0 +1,83 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+use easyharun_lib::ContainerId;
+
+pub const DEFAULT_GC_RETENTION_S: u64 = 3600;
+pub const DEFAULT_GC_KEEP_EXITED: usize = 3;
+
+// the gc does not need to run on every tick.
+pub const GC_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+pub struct GcOptions {
+    // exited containers are removed once they exited this long ago ...
+    pub retention_s: u64,
+    // ... except for the latest ones of every container, they are kept for debugging.
+    pub keep_exited: usize,
+}
+
+impl Default for GcOptions {
+    fn default() -> Self {
+        Self {
+            retention_s: DEFAULT_GC_RETENTION_S,
+            keep_exited: DEFAULT_GC_KEEP_EXITED,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ExitedContainer {
+    pub container_id: ContainerId,
+    pub name: String,
+    // unix timestamp docker reports for the creation.
+    pub created: i64,
+    pub exited_at: Instant,
+}
+
+pub fn select_exited_containers_to_remove(exited: Vec<ExitedContainer>, options: &GcOptions, now: Instant) -> Vec<ExitedContainer> {
+    let mut groups : BTreeMap<String, Vec<ExitedContainer>> = BTreeMap::new();
+    for container in exited {
+        groups.entry(container.name.clone()).or_default().push(container);
+    }
+
+    let retention = Duration::from_secs(options.retention_s);
+
+    groups.into_values().flat_map(|mut group| {
+        group.sort_by_key(|c| ::std::cmp::Reverse(c.created));
+        group.into_iter()
+            .skip(options.keep_exited)
+            .filter(|c| now.duration_since(c.exited_at) >= retention)
+            .collect::<Vec<_>>()
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keep_the_latest_exited_containers() {
+        let now = Instant::now();
+        let options = GcOptions { retention_s: 60, keep_exited: 2 };
+
+        let exited = |name: &str, created: i64, exited_at: Instant| ExitedContainer {
+            container_id: ContainerId::new(format!("{}-{}", name, created)),
+            name: name.to_string(),
+            created,
+            exited_at,
+        };
+
+        let containers = vec![
+            exited("web", 1, now),
+            exited("web", 2, now),
+            exited("web", 3, now + Duration::from_secs(50)),
+            exited("web", 4, now),
+            exited("db", 1, now),
+        ];
+
+        let removed = select_exited_containers_to_remove(containers, &options, now + Duration::from_secs(60));
+
+        // web-4 and web-3 are kept as the latest, db-1 is the only one of its container.
+        assert_eq!(vec!["web-2", "web-1"], removed.iter().map(|c| c.container_id.as_str()).collect::<Vec<_>>());
+    }
+}
@@ -1,29 +1,79 @@
-use std::time::Duration;
-use anyhow::{Context, Error};
-use tracing::{debug, info};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant, SystemTime};
+use anyhow::{anyhow, Context, Error};
+use tracing::{debug, info, warn};
 use easyact::{Actor, ActorState};
 use crate::brain::brain::Brain;
+use crate::brain::brain_action::{BrainAction, ContainerStop};
 use crate::config::config_world_builder::build_world_from_config;
-use crate::container_manager::world::Worlds;
+use crate::container_manager::world::{World, Worlds};
 use crate::docker::docker_action_executer::DockerActionExecuter;
 use crate::docker::docker_world_builder::build_world_from_docker;
 use async_trait::async_trait;
-use crate::config::config_provider::ConfigReader;
+use tokio::task::JoinHandle;
+use crate::config::config_provider::{ConfigChanged, ConfigReader};
+use crate::container_manager::canary::{apply_canary_decisions, build_canary_status, get_canary_revisions};
+use crate::container_manager::crash_loop::hold_back_crashed_starts;
+use crate::container_manager::gc::{GcOptions, GC_INTERVAL};
+use crate::container_manager::rollback::Rollbacks;
+use crate::container_manager::unhealthy::think_about_replacing_unhealthy;
+use crate::kv_container::state::ContainerReplacement;
+use crate::container_runtime::ContainerRuntimeRef;
+use crate::container_watcher::ContainersChanged;
 use crate::kv_container::KV;
 
+pub mod canary;
+pub mod crash_loop;
+pub mod gc;
+pub mod rollback;
+pub mod unhealthy;
 pub mod world;
 
+#[derive(Debug)]
+pub enum ContainerManagerMsg {
+    ConfigChanged(ConfigChanged),
+    ContainersChanged(ContainersChanged),
+}
+
 #[derive(Debug)]
 pub struct ContainerManager {
-    pub actor_state: ActorState<()>,
-    pub config_reader: ConfigReader,
-    pub kv: KV,
+    actor_state: ActorState<ContainerManagerMsg>,
+    config_reader: ConfigReader,
+    config_generation_applied: u64,
+    live_revisions: HashMap<String, String>,
+    max_parallel_actions: usize,
+    gc_options: GcOptions,
+    last_gc: Option<Instant>,
+    // starts and stops of the last plan, the next plan waits for them. a slow pull must not block the ticks.
+    actions_in_flight: Option<JoinHandle<()>>,
+    // containers of older label schemas are adopted and the state of vanished containers is pruned once, before the first reconcile.
+    started: bool,
+    runtime: ContainerRuntimeRef,
+    kv: KV,
+}
+
+impl ContainerManager {
+    pub fn new(actor_state: ActorState<ContainerManagerMsg>, config_reader: ConfigReader, runtime: ContainerRuntimeRef, kv: KV, max_parallel_actions: usize, gc_options: GcOptions) -> Self {
+        Self {
+            actor_state,
+            config_reader,
+            config_generation_applied: 0,
+            live_revisions: HashMap::new(),
+            max_parallel_actions,
+            gc_options,
+            last_gc: None,
+            actions_in_flight: None,
+            started: false,
+            runtime,
+            kv
+        }
+    }
 }
 
 
 #[async_trait]
 impl Actor for ContainerManager {
-    type MSG = ();
+    type MSG = ContainerManagerMsg;
 
     fn get_actor_state(&mut self) -> &mut ActorState<Self::MSG> {
         &mut self.actor_state
@@ -34,24 +84,160 @@ impl Actor for ContainerManager {
     }
 
     async fn on_timer(&mut self) -> Result<(), Error> {
+        self.run_inner().await
+    }
+
+    async fn on_msg(&mut self, msg: Self::MSG) -> Result<(), Error> {
+        match msg {
+            ContainerManagerMsg::ConfigChanged(msg) => debug!("config changed to generation {}", msg.generation),
+            ContainerManagerMsg::ContainersChanged(msg) => debug!("containers changed, generation {}", msg.generation),
+        };
+
+        self.run_inner().await
+    }
+}
+
+impl ContainerManager {
+    async fn run_inner(&mut self) -> Result<(), Error> {
+
+        let docker_action_executer = DockerActionExecuter::new(self.kv.clone(), self.runtime.clone(), self.max_parallel_actions);
+
+        if !self.started {
+            docker_action_executer.prune_vanished_containers().await.context("could not prune the state of vanished containers")?;
+            docker_action_executer.adopt_older_label_schemas().await.context("could not adopt containers of older label schemas")?;
+            self.started = true;
+        }
+
+        docker_action_executer.collect_exited_containers().await.context("could not collect exited containers")?;
+
+        let config_snapshot = self.config_reader.get_snapshot();
+
+        let current = build_world_from_docker(self.runtime.as_ref(), &self.kv).await.context("could not build world from docker")?;
+        let expected = build_world_from_config(&config_snapshot.config).await.context("could not build world from config")?;
+        self.kv.retain_canary_decisions(&get_canary_revisions(&expected)).await;
+        let expected = apply_canary_decisions(expected, &self.kv.get_canary_decisions().await);
+        let current = self.replace_unhealthy_containers(&docker_action_executer, current, &expected).await?;
+
+        let mut rollbacks = Rollbacks::new(self.kv.get_container_groups().await);
 
         let worlds = Worlds {
-            expected: build_world_from_config(&self.config_reader).await.context("could not build world from config")?,
-            current: build_world_from_docker(&self.kv).await.context("could not build world from docker")?
+            expected: rollbacks.apply(expected, &current, Instant::now()),
+            current,
         };
 
+        let status = rollbacks.get_status();
+        self.kv.set_container_groups(rollbacks.into_groups(), status).await;
+        self.kv.set_canary_status(build_canary_status(&worlds.expected, &worlds.current, &self.kv).await).await;
+
         debug!("created worlds");
 
-        let next_action = Brain::think_about_next_action(&worlds).context("brain error, could not resolve brain action.")?;
+        let live_revisions = Brain::think_about_live_revisions(&worlds, &self.live_revisions);
+        if live_revisions != self.live_revisions {
+            info!("proxies switch to revisions {:?}", live_revisions);
+            self.live_revisions = live_revisions.clone();
+            self.kv.set_live_revisions(live_revisions).await;
+        }
 
-        info!("execute action {:?}", next_action);
-        DockerActionExecuter::new(self.kv.clone()).execute(&next_action).await.context("docker action executer")?;
+        if self.last_gc.map(|t| t.elapsed() >= GC_INTERVAL).unwrap_or(true) {
+            self.last_gc = Some(Instant::now());
 
-        Ok(())
+            let expected_images = worlds.expected.get_containers().iter().map(|c| c.image.clone()).collect::<HashSet<_>>();
+            if let Err(e) = docker_action_executer.collect_garbage(&self.gc_options, &expected_images).await {
+                warn!("could not collect garbage: {:#}", e);
+            }
+        }
 
-    }
+        self.kv.retain_crash_loops(&worlds.expected.get_containers().iter().map(|c| c.get_identifier()).collect::<HashSet<_>>()).await;
+
+        // the current world does not show the running actions yet, a new plan would repeat them.
+        if self.actions_in_flight.as_ref().map(|a| !a.is_finished()).unwrap_or(false) {
+            debug!("docker actions of the last plan are still running");
+            return Ok(());
+        }
+
+        let next_actions = Brain::think_about_next_actions(&worlds).context("brain error, could not resolve brain actions.")?;
+        let next_actions = hold_back_crashed_starts(next_actions, &self.kv.get_crash_loops().await, Instant::now());
+
+        for next_action in next_actions.iter() {
+            info!("execute action {}", config_snapshot.config.redactor.redact(&format!("{:?}", next_action)));
+        }
+
+        let has_next_actions = !next_actions.is_empty();
+        let redactor = config_snapshot.config.redactor.clone();
+
+        self.actions_in_flight = Some(::tokio::spawn(async move {
+            if let Err(e) = docker_action_executer.execute_pending_container_stops().await {
+                warn!("could not stop containers: {:#}", e);
+            }
+
+            if let Err(errors) = docker_action_executer.execute(&next_actions).await {
+                for e in errors.iter() {
+                    warn!("docker action failed: {}", redactor.redact(&format!("{:#}", e)));
+                }
+
+                warn!("{} of the docker actions failed", errors.len());
+            }
+        }));
+
+        // the config is applied once the brain has nothing left to do.
+        if !has_next_actions && self.config_generation_applied != config_snapshot.generation {
+            info!("applied config generation {}", config_snapshot.generation);
+            self.config_generation_applied = config_snapshot.generation;
+            self.config_reader.set_applied_generation("ContainerManager", config_snapshot.generation).await;
+        }
 
-    async fn on_msg(&mut self, msg: Self::MSG) -> Result<(), Error> {
         Ok(())
     }
+
+    // stops replicas that keep failing their health checks, the brain starts new ones in their place.
+    async fn replace_unhealthy_containers(&self, docker_action_executer: &DockerActionExecuter, current: World, expected: &World) -> Result<World, Error> {
+        let now = Instant::now();
+        let mut replacements = vec![];
+
+        for container in current.get_containers() {
+            let container_id = match &container.container_id {
+                Some(s) => s,
+                None => continue,
+            };
+
+            let policy = match expected.get_containers().iter().find(|e| e.get_identifier() == container.get_identifier()) {
+                Some(s) => s,
+                None => continue,
+            };
+
+            let failures = self.kv.get_health_failures(container_id).await;
+
+            if let Some(reason) = think_about_replacing_unhealthy(policy, &failures, now) {
+                replacements.push((ContainerStop::new_from_world_container(container)?, reason));
+            }
+        }
+
+        if replacements.is_empty() {
+            return Ok(current);
+        }
+
+        for (stop, reason) in replacements.iter() {
+            warn!("replace container {} replica {} ({}): {}", stop.world_container.name, stop.world_container.replica_id, stop.id.as_str(), reason);
+
+            self.kv.record_container_replacement(ContainerReplacement {
+                name: stop.world_container.name.clone(),
+                replica_id: stop.world_container.replica_id,
+                container_id: stop.id.as_str().to_string(),
+                reason: reason.clone(),
+                replaced_at: SystemTime::now(),
+            }).await;
+        }
+
+        let stops = replacements.into_iter().map(|(stop, _)| stop).collect::<Vec<_>>();
+
+        if let Err(errors) = docker_action_executer.execute(&[BrainAction::ContainersStop(stops.clone())]).await {
+            return Err(anyhow!("could not replace unhealthy containers: {:#}", errors[0]));
+        }
+
+        Ok(World::new(current.get_containers().iter()
+            .filter(|c| !stops.iter().any(|s| c.container_id.as_ref() == Some(&s.id)))
+            .cloned()
+            .collect()
+        ))
+    }
 }
\ No newline at end of file
@@ -0,0 +1,265 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+use crate::container_manager::world::{World, WorldContainer};
+use crate::kv_container::state::{ContainerGroupHistory, ContainerGroupRevision, ContainerGroupState, ContainerGroupStatus};
+
+// the history is persisted, older failed revisions are forgotten. the config rarely goes back that far.
+const MAX_FAILED_REVISIONS: usize = 10;
+
+// remembers the last healthy revision of every container group.
+// a revision that does not become ready within update.rollback_after_s is replaced by it.
+#[derive(Debug, Default)]
+pub struct Rollbacks {
+    groups: BTreeMap<String, ContainerGroupHistory>,
+}
+
+impl Rollbacks {
+
+    // the history is kept in the KV between ticks.
+    pub fn new(groups: BTreeMap<String, ContainerGroupHistory>) -> Self {
+        Self {
+            groups,
+        }
+    }
+
+    pub fn into_groups(self) -> BTreeMap<String, ContainerGroupHistory> {
+        self.groups
+    }
+
+    pub fn apply(&mut self, expected: World, current: &World, now: Instant) -> World {
+        let mut groups : BTreeMap<String, Vec<WorldContainer>> = BTreeMap::new();
+        for container in expected.get_containers() {
+            groups.entry(container.name.clone()).or_default().push(container.clone());
+        }
+
+        self.groups.retain(|name, _| groups.contains_key(name));
+
+        let mut containers = vec![];
+
+        for (name, group) in groups {
+            containers.extend(self.apply_group(name, group, current, now));
+        }
+
+        World::new(containers)
+    }
+
+    fn apply_group(&mut self, name: String, containers: Vec<WorldContainer>, current: &World, now: Instant) -> Vec<WorldContainer> {
+        let revision = match containers.first() {
+            Some(s) => s.compute_revision(),
+            None => return containers,
+        };
+
+        let rollback_after_s = containers[0].update.rollback_after_s;
+
+        let new_revision = ContainerGroupRevision {
+            revision: revision.clone(),
+            containers: containers.clone(),
+            since: now,
+        };
+
+        let history = self.groups.entry(name.clone()).or_insert_with(|| ContainerGroupHistory {
+            state: ContainerGroupState::RollingOut,
+            current: new_revision.clone(),
+            healthy: None,
+            failed_revisions: vec![],
+        });
+
+        if history.failed_revisions.contains(&revision) {
+            if let Some(healthy) = &history.healthy {
+                match restore_env_values(healthy, &containers) {
+                    Some(s) => return s,
+                    None => {
+                        warn!("container {} can not stay on revision {}, the config does not have its env values anymore", name, healthy.revision);
+                        history.healthy = None;
+                    }
+                };
+            }
+        }
+
+        if history.current.revision != revision {
+            info!("container {} rolls out revision {}", name, revision);
+            history.current = new_revision;
+            history.state = ContainerGroupState::RollingOut;
+        }
+
+        let all_ready = containers.iter().all(|c| {
+            let spec_hash = c.compute_spec_hash();
+            current.get_containers().iter().any(|w| w.ready && w.get_identifier() == c.get_identifier() && w.has_spec_hash(&spec_hash))
+        });
+
+        if all_ready {
+            if history.state != ContainerGroupState::Healthy {
+                info!("container {} revision {} is healthy", name, revision);
+            }
+            history.state = ContainerGroupState::Healthy;
+            history.healthy = Some(history.current.clone());
+            return containers;
+        }
+
+        let deadline_exceeded = match rollback_after_s {
+            Some(s) => now.duration_since(history.current.since) >= Duration::from_secs(s as u64),
+            None => false,
+        };
+
+        let healthy = match &history.healthy {
+            Some(healthy) if deadline_exceeded && healthy.revision != revision => healthy,
+            _ => return containers,
+        };
+
+        match restore_env_values(healthy, &containers) {
+            Some(restored) => {
+                warn!("container {} revision {} did not become healthy within {}s, rolling back to revision {}", name, revision, rollback_after_s.unwrap_or_default(), healthy.revision);
+                history.failed_revisions.push(revision);
+                let forgotten = history.failed_revisions.len().saturating_sub(MAX_FAILED_REVISIONS);
+                history.failed_revisions.drain(..forgotten);
+                history.state = ContainerGroupState::RolledBack;
+                restored
+            },
+            None => {
+                warn!("container {} can not roll back to revision {}, the config does not have its env values anymore", name, healthy.revision);
+                history.healthy = None;
+                containers
+            }
+        }
+    }
+
+    pub fn get_status(&self) -> Vec<ContainerGroupStatus> {
+        self.groups.iter().map(|(name, history)| ContainerGroupStatus {
+            name: name.to_string(),
+            state: history.state.clone(),
+            revision: history.current.revision.clone(),
+            healthy_revision: history.healthy.as_ref().map(|h| h.revision.clone()),
+            failed_revisions: history.failed_revisions.clone(),
+        }).collect()
+    }
+}
+
+// the history read from the state only has the env keys, the values come from the config again.
+// None if the values of the config are not the ones of the revision.
+fn restore_env_values(revision: &ContainerGroupRevision, config: &[WorldContainer]) -> Option<Vec<WorldContainer>> {
+    revision.containers.iter().map(|c| {
+        if c.compute_revision() == revision.revision {
+            return Some(c.clone());
+        }
+
+        let mut container = c.clone();
+
+        for (key, value) in container.runtime.env.iter_mut() {
+            if let Some(s) = config.first().and_then(|e| e.runtime.env.get(key)) {
+                *value = s.to_string();
+            }
+        }
+
+        match container.compute_revision() == revision.revision {
+            true => Some(container),
+            false => None,
+        }
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use easyharun_lib::config::{ConfigContainerRuntime, ConfigContainerUpdate};
+    use crate::kv_container::KV;
+    use super::*;
+
+    fn container(image: &str, ready: bool) -> WorldContainer {
+        let mut container = WorldContainer {
+            name: "web".to_string(),
+            image: image.to_string(),
+            container_ports: vec![80],
+            update: ConfigContainerUpdate { rollback_after_s: Some(60), ..Default::default() },
+            runtime: ConfigContainerRuntime {
+                env: BTreeMap::from([("DB_PASSWORD".to_string(), "secret".to_string())]),
+                ..Default::default()
+            },
+            ready,
+            ..Default::default()
+        };
+        container.spec_hash = Some(container.compute_spec_hash());
+        container
+    }
+
+    #[test]
+    fn rollback_to_the_last_healthy_revision() {
+        let mut rollbacks = Rollbacks::default();
+        let now = Instant::now();
+
+        let expected = rollbacks.apply(World::new(vec![container("web:1", false)]), &World::new(vec![container("web:1", true)]), now);
+        assert_eq!("web:1", expected.get_containers()[0].image);
+        assert_eq!(ContainerGroupState::Healthy, rollbacks.get_status()[0].state);
+
+        let current = World::new(vec![container("web:1", true), container("web:2", false)]);
+
+        let expected = rollbacks.apply(World::new(vec![container("web:2", false)]), &current, now + Duration::from_secs(1));
+        assert_eq!("web:2", expected.get_containers()[0].image);
+        assert_eq!(ContainerGroupState::RollingOut, rollbacks.get_status()[0].state);
+
+        let expected = rollbacks.apply(World::new(vec![container("web:2", false)]), &current, now + Duration::from_secs(61));
+        assert_eq!("web:1", expected.get_containers()[0].image);
+
+        let status = &rollbacks.get_status()[0];
+        assert_eq!(ContainerGroupState::RolledBack, status.state);
+        assert_eq!(vec![container("web:2", false).compute_revision()], status.failed_revisions);
+
+        // the failed revision stays rolled back until the config changes.
+        let expected = rollbacks.apply(World::new(vec![container("web:2", false)]), &current, now + Duration::from_secs(600));
+        assert_eq!("web:1", expected.get_containers()[0].image);
+    }
+
+    #[test]
+    fn only_the_last_failed_revisions_are_kept() {
+        let mut rollbacks = Rollbacks::default();
+        let now = Instant::now();
+
+        rollbacks.apply(World::new(vec![container("web:1", false)]), &World::new(vec![container("web:1", true)]), now);
+
+        for i in 2..MAX_FAILED_REVISIONS + 4 {
+            let image = format!("web:{}", i);
+            let current = World::new(vec![container("web:1", true), container(&image, false)]);
+            let since = now + Duration::from_secs(i as u64 * 100);
+
+            rollbacks.apply(World::new(vec![container(&image, false)]), &current, since);
+            let expected = rollbacks.apply(World::new(vec![container(&image, false)]), &current, since + Duration::from_secs(61));
+            assert_eq!("web:1", expected.get_containers()[0].image);
+        }
+
+        let failed_revisions = &rollbacks.get_status()[0].failed_revisions;
+        assert_eq!(MAX_FAILED_REVISIONS, failed_revisions.len());
+        assert!(!failed_revisions.contains(&container("web:2", false).compute_revision()));
+        assert_eq!(Some(&container(&format!("web:{}", MAX_FAILED_REVISIONS + 3), false).compute_revision()), failed_revisions.last());
+    }
+
+    #[tokio::test]
+    async fn the_last_healthy_revision_survives_a_restart() {
+        let dir = ::std::env::temp_dir().join(format!("easyharun-state-{}", uuid::Uuid::new_v4()));
+        ::std::fs::create_dir_all(&dir).expect("state dir");
+        let now = Instant::now();
+
+        {
+            let kv = KV::open(&dir).expect("opened");
+            let mut rollbacks = Rollbacks::new(kv.get_container_groups().await);
+            rollbacks.apply(World::new(vec![container("web:1", false)]), &World::new(vec![container("web:1", true)]), now);
+
+            let status = rollbacks.get_status();
+            kv.set_container_groups(rollbacks.into_groups(), status).await;
+        }
+
+        let state = ::std::fs::read_to_string(dir.join("state.json")).expect("state");
+        assert!(state.contains("DB_PASSWORD"));
+        assert!(!state.contains("secret"));
+
+        let kv = KV::open(&dir).expect("reopened");
+        let mut rollbacks = Rollbacks::new(kv.get_container_groups().await);
+        let current = World::new(vec![container("web:1", true), container("web:2", false)]);
+
+        rollbacks.apply(World::new(vec![container("web:2", false)]), &current, now + Duration::from_secs(1));
+        let expected = rollbacks.apply(World::new(vec![container("web:2", false)]), &current, now + Duration::from_secs(61));
+        assert_eq!("web:1", expected.get_containers()[0].image);
+        assert_eq!(Some(&"secret".to_string()), expected.get_containers()[0].runtime.env.get("DB_PASSWORD"));
+        assert_eq!(ContainerGroupState::RolledBack, rollbacks.get_status()[0].state);
+
+        ::std::fs::remove_dir_all(&dir).expect("removed");
+    }
+}
@@ -0,0 +1,44 @@
+use std::time::{Duration, Instant};
+use crate::container_manager::world::WorldContainer;
+use crate::kv_container::state::HealthFailures;
+
+// expected holds the policy of the replica, it is not part of the container labels.
+pub fn think_about_replacing_unhealthy(expected: &WorldContainer, failures: &HealthFailures, now: Instant) -> Option<String> {
+    let restart_after_failures = expected.restart_after_failures?;
+
+    if failures.consecutive < restart_after_failures {
+        return None;
+    }
+
+    let unhealthy_for = now.duration_since(failures.unhealthy_since?);
+
+    if unhealthy_for < Duration::from_secs(expected.unhealthy_grace_s as u64) {
+        return None;
+    }
+
+    Some(format!("{} health checks failed in a row, unhealthy for {}s", failures.consecutive, unhealthy_for.as_secs()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replace_after_failures_and_grace() {
+        let expected = WorldContainer {
+            restart_after_failures: Some(3),
+            unhealthy_grace_s: 30,
+            ..Default::default()
+        };
+
+        let now = Instant::now();
+        let failures = |consecutive| HealthFailures { consecutive, unhealthy_since: Some(now) };
+
+        assert_eq!(None, think_about_replacing_unhealthy(&expected, &failures(2), now + Duration::from_secs(60)));
+        assert_eq!(None, think_about_replacing_unhealthy(&expected, &failures(3), now + Duration::from_secs(29)));
+        assert!(think_about_replacing_unhealthy(&expected, &failures(3), now + Duration::from_secs(30)).is_some());
+
+        let without_policy = WorldContainer::default();
+        assert_eq!(None, think_about_replacing_unhealthy(&without_policy, &failures(100), now + Duration::from_secs(600)));
+    }
+}
@@ -1,6 +1,9 @@
 use std::collections::{HashMap};
-use easyharun_lib::config::ConfigContainerProxy;
+use easyharun_lib::config::{ConfigContainerProxy, ConfigContainerRuntime, ConfigContainerUpdate};
+use easyharun_lib::container_labels::ContainerLabels;
 use easyharun_lib::ContainerId;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use crate::docker::docker_world_builder::PortInternalDynamic;
 
 
@@ -26,36 +29,39 @@ impl WorldDiff {
 
 impl Worlds {
     pub fn build_diff_world(&self) -> WorldDiff {
-        let current_map = {
-            let mut map = HashMap::new();
-            for c in &self.current.containers {
-                map.insert(c.get_identifier(), c.clone());
-            }
-            map
-        };
+        let mut current_map : HashMap<String, Vec<&WorldContainer>> = HashMap::new();
+        for c in &self.current.containers {
+            current_map.entry(c.get_identifier()).or_default().push(c);
+        }
 
-        let expected_map = {
-            let mut map = HashMap::new();
-            for c in &self.expected.containers {
-                map.insert(c.get_identifier(), c.clone());
-            }
-            map
-        };
+        let mut expected_map : HashMap<String, Vec<&WorldContainer>> = HashMap::new();
+        for c in &self.expected.containers {
+            expected_map.entry(c.get_identifier()).or_default().push(c);
+        }
 
         let mut containers_exists_but_should_not_exists = vec![];
 
-        for (container_identifier, container) in &current_map {
+        for (container_identifier, containers) in &current_map {
             if !expected_map.contains_key(container_identifier) {
-                containers_exists_but_should_not_exists.push(container.clone());
+                containers_exists_but_should_not_exists.extend(containers.iter().map(|c| (*c).clone()));
             }
         }
 
         let mut containers_does_not_exists_but_should_exists = vec![];
 
-        for (container_identifier, container) in &expected_map {
-            if !current_map.contains_key(container_identifier) {
-                containers_does_not_exists_but_should_exists.push(container.clone());
+        for (container_identifier, expected_containers) in &expected_map {
+            let mut current_containers = current_map.get(container_identifier).cloned().unwrap_or_default();
+
+            for expected_container in expected_containers {
+                // a replica with a different spec hash is replaced, the old one goes once it is not matched anymore.
+                let spec_hash = expected_container.compute_spec_hash();
+                match current_containers.iter().position(|c| c.has_spec_hash(&spec_hash)) {
+                    Some(pos) => { current_containers.remove(pos); },
+                    None => containers_does_not_exists_but_should_exists.push((*expected_container).clone()),
+                };
             }
+
+            containers_exists_but_should_not_exists.extend(current_containers.into_iter().cloned());
         }
 
         WorldDiff {
@@ -82,9 +88,10 @@ impl World {
     }
 }
 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct WorldContainer {
     pub container_id: Option<ContainerId>,
+    #[serde(skip)]
     pub container_port_mapping: Option<Vec<PortInternalDynamic>>,
     pub name: String,
     pub image: String,
@@ -92,12 +99,99 @@ pub struct WorldContainer {
     pub container_ports: Vec<u32>,
     pub health_checks: Vec<String>,
     pub proxies: Vec<ConfigContainerProxy>,
+    pub stop_grace_period_s: u32,
+    pub restart_after_failures: Option<u32>,
+    pub unhealthy_grace_s: u32,
+    pub runtime: ConfigContainerRuntime,
+    pub update: ConfigContainerUpdate,
+    // percent of the traffic, only set for canary replicas.
+    pub canary_weight: Option<u32>,
+    // hash of the spec the container was started with, read from its label.
+    pub spec_hash: Option<String>,
+    // revision the container was started with, read from its label.
+    pub revision: Option<String>,
+    // all health checks passed.
+    pub healthy: bool,
+    // healthy and all proxies route to the container.
+    pub ready: bool,
+}
+
+// everything a container is started with, a change to any of it replaces the container.
+#[derive(Serialize)]
+struct WorldContainerSpec<'a> {
+    name: &'a str,
+    image: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    replica_id: Option<u32>,
+    container_ports: &'a Vec<u32>,
+    health_checks: &'a Vec<String>,
+    proxies: &'a Vec<ConfigContainerProxy>,
+    stop_grace_period_s: u32,
+    runtime: &'a ConfigContainerRuntime,
 }
 
 impl WorldContainer {
     pub fn get_identifier(&self) -> String {
         format!("{}|{}|{}|{}", self.name, self.image, self.replica_id, self.container_ports.iter().map(|x|x.to_string()).collect::<Vec<String>>().join(","))
     }
+
+    pub fn compute_spec_hash(&self) -> String {
+        self.compute_hash(Some(self.replica_id))
+    }
+
+    // same for all replicas of a container.
+    pub fn compute_revision(&self) -> String {
+        self.compute_hash(None)
+    }
+
+    fn compute_hash(&self, replica_id: Option<u32>) -> String {
+        let spec = WorldContainerSpec {
+            name: &self.name,
+            image: &self.image,
+            replica_id,
+            container_ports: &self.container_ports,
+            health_checks: &self.health_checks,
+            proxies: &self.proxies,
+            stop_grace_period_s: self.stop_grace_period_s,
+            runtime: &self.runtime,
+        };
+
+        // maps in the spec are BTreeMaps, so the json is stable.
+        let json = ::serde_json::to_string(&spec).expect("container spec is serializable");
+
+        Sha256::digest(json.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    // containers read from the runtime always have a hash, world_container_from_labels computes a missing one.
+    pub fn has_spec_hash(&self, spec_hash: &str) -> bool {
+        match &self.spec_hash {
+            Some(s) => s == spec_hash,
+            None => true,
+        }
+    }
+
+    // the labels the container is started with.
+    pub fn get_container_labels(&self) -> ContainerLabels {
+        ContainerLabels {
+            name: self.name.clone(),
+            image: self.image.clone(),
+            replica_id: self.replica_id,
+            container_ports: self.container_ports.clone(),
+            health_checks: self.health_checks.clone(),
+            proxies: self.proxies.clone(),
+            stop_grace_period_s: self.stop_grace_period_s,
+            runtime: self.runtime.clone(),
+            spec_hash: Some(self.compute_spec_hash()),
+            revision: Some(self.compute_revision()),
+        }
+    }
+
+    pub fn get_server_addrs(&self) -> Vec<String> {
+        match &self.container_port_mapping {
+            Some(s) => s.iter().map(|p| p.get_server_addr()).collect(),
+            None => vec![],
+        }
+    }
 }
 
 #[cfg(test)]
@@ -235,4 +329,43 @@ mod tests {
         assert_eq!(1, diff.containers_exists_but_should_not_exists.len());
         assert_eq!(0, diff.containers_does_not_exists_but_should_exists.len());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn build_world_diff_spec_drift_replaces_container() {
+
+        let expected = WorldContainer {
+            name: "1".to_string(),
+            container_ports: vec![80],
+            image: "foo:latest".to_string(),
+            ..Default::default()
+        };
+
+        let mut drifted = expected.clone();
+        drifted.spec_hash = Some("outdated".to_string());
+
+        let worlds = Worlds {
+            expected: World::new(vec![expected.clone()]),
+            current: World::new(vec![drifted]),
+        };
+
+        let diff = worlds.build_diff_world();
+
+        assert_eq!(1, diff.containers_exists_but_should_not_exists.len());
+        assert_eq!(1, diff.containers_does_not_exists_but_should_exists.len());
+
+        // once the replacement runs, only the drifted container is left to stop.
+        let mut replacement = expected.clone();
+        replacement.spec_hash = Some(expected.compute_spec_hash());
+
+        let worlds = Worlds {
+            expected: World::new(vec![expected]),
+            current: World::new(vec![worlds.current.containers[0].clone(), replacement]),
+        };
+
+        let diff = worlds.build_diff_world();
+
+        assert_eq!(Some("outdated".to_string()), diff.containers_exists_but_should_not_exists[0].spec_hash);
+        assert_eq!(1, diff.containers_exists_but_should_not_exists.len());
+        assert_eq!(0, diff.containers_does_not_exists_but_should_exists.len());
+    }
+}
@@ -0,0 +1,322 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Mutex};
+use anyhow::anyhow;
+use async_trait::async_trait;
+use bollard::container::Config;
+use bollard::models::{ContainerInspectResponse, ContainerState, ContainerStateStatusEnum, ContainerSummary, EventActor, EventMessage, EventMessageTypeEnum, Port, PortTypeEnum};
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use easyharun_lib::ContainerId;
+use crate::container_runtime::{ContainerRuntime, ImagePull};
+
+const FAKE_FIRST_HOST_PORT: i64 = 40000;
+
+#[derive(Debug, Clone)]
+struct FakeContainer {
+    id: String,
+    name: String,
+    config: Config<String>,
+    state: ContainerStateStatusEnum,
+    created: i64,
+    ports: Vec<Port>,
+    exit_code: Option<i64>,
+    logs: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+struct FakeContainerRuntimeState {
+    containers: BTreeMap<String, FakeContainer>,
+    images: BTreeSet<String>,
+    // ids, creation times and host ports are counted up, so tests are deterministic.
+    next_id: i64,
+}
+
+// keeps containers in memory, tests drive it through the ContainerRuntime trait
+// and simulate crashes with exit_container.
+#[derive(Debug, Clone)]
+pub struct FakeContainerRuntime {
+    state: Arc<Mutex<FakeContainerRuntimeState>>,
+    events: broadcast::Sender<EventMessage>,
+}
+
+impl FakeContainerRuntime {
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(FakeContainerRuntimeState::default())),
+            events: broadcast::channel(1024).0,
+        }
+    }
+
+    // the container exits on its own, like a crashing process.
+    pub fn exit_container(&self, container_id: &str, exit_code: i64, logs: Vec<String>) -> Result<(), ::anyhow::Error> {
+        let mut state = self.state.lock().expect("fake runtime lock");
+
+        let container = state.containers.values_mut()
+            .find(|c| c.id == container_id)
+            .ok_or_else(|| anyhow!("no such container {}", container_id))?;
+
+        container.state = ContainerStateStatusEnum::EXITED;
+        container.exit_code = Some(exit_code);
+        container.logs.extend(logs);
+
+        let container = container.clone();
+        drop(state);
+
+        self.send_event("die", &container);
+
+        Ok(())
+    }
+
+    pub fn get_images(&self) -> BTreeSet<String> {
+        self.state.lock().expect("fake runtime lock").images.clone()
+    }
+
+    fn send_event(&self, action: &str, container: &FakeContainer) {
+        // nobody listens, that is fine.
+        let _ = self.events.send(EventMessage {
+            typ: Some(EventMessageTypeEnum::CONTAINER),
+            action: Some(action.to_string()),
+            actor: Some(EventActor {
+                id: Some(container.id.clone()),
+                attributes: container.config.labels.clone(),
+            }),
+            ..Default::default()
+        });
+    }
+
+    fn find<'a>(state: &'a mut FakeContainerRuntimeState, name_or_id: &str) -> Result<&'a mut FakeContainer, ::anyhow::Error> {
+        state.containers.values_mut()
+            .find(|c| c.id == name_or_id || c.name == name_or_id)
+            .ok_or_else(|| anyhow!("no such container {}", name_or_id))
+    }
+}
+
+impl Default for FakeContainerRuntime {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl ContainerRuntime for FakeContainerRuntime {
+    async fn list_containers(&self) -> Result<Vec<ContainerSummary>, ::anyhow::Error> {
+        let state = self.state.lock().expect("fake runtime lock");
+
+        Ok(state.containers.values().map(|c| ContainerSummary {
+            id: Some(c.id.clone()),
+            names: Some(vec![format!("/{}", c.name)]),
+            image: c.config.image.clone(),
+            created: Some(c.created),
+            ports: Some(c.ports.clone()),
+            labels: c.config.labels.clone(),
+            state: Some(c.state.to_string()),
+            ..Default::default()
+        }).collect())
+    }
+
+    async fn pull_image(&self, image: &str) -> Result<ImagePull, ::anyhow::Error> {
+        match self.state.lock().expect("fake runtime lock").images.insert(image.to_string()) {
+            true => Ok(ImagePull::Pulled),
+            false => Ok(ImagePull::AlreadyPresent),
+        }
+    }
+
+    async fn create_container(&self, name: &str, config: Config<String>) -> Result<(), ::anyhow::Error> {
+        let mut state = self.state.lock().expect("fake runtime lock");
+
+        let image = config.image.clone().unwrap_or_default();
+        if !state.images.contains(&image) {
+            return Err(anyhow!("no such image {}", image));
+        }
+
+        if state.containers.contains_key(name) {
+            return Err(anyhow!("container name {} is already in use", name));
+        }
+
+        state.next_id += 1;
+
+        let container = FakeContainer {
+            id: format!("fake{:08}", state.next_id),
+            name: name.to_string(),
+            config,
+            state: ContainerStateStatusEnum::CREATED,
+            created: state.next_id,
+            ports: vec![],
+            exit_code: None,
+            logs: vec![],
+        };
+
+        state.containers.insert(name.to_string(), container.clone());
+        drop(state);
+
+        self.send_event("create", &container);
+
+        Ok(())
+    }
+
+    async fn start_container(&self, name: &str) -> Result<(), ::anyhow::Error> {
+        let mut state = self.state.lock().expect("fake runtime lock");
+        let next_id = state.next_id;
+
+        let container = Self::find(&mut state, name)?;
+
+        let exposed_ports = container.config.exposed_ports.clone().unwrap_or_default();
+
+        // every container gets its own range of host ports.
+        container.ports = exposed_ports.keys().enumerate().filter_map(|(i, port)| Some(Port {
+            ip: Some("0.0.0.0".to_string()),
+            private_port: port.split('/').next()?.parse().ok()?,
+            public_port: Some(FAKE_FIRST_HOST_PORT + next_id * 10 + i as i64),
+            typ: Some(PortTypeEnum::TCP),
+        })).collect();
+        container.state = ContainerStateStatusEnum::RUNNING;
+
+        let container = container.clone();
+        drop(state);
+
+        self.send_event("start", &container);
+
+        Ok(())
+    }
+
+    async fn stop_container(&self, container_id: &ContainerId, stop_grace_period_s: u32) -> Result<(), ::anyhow::Error> {
+        let mut state = self.state.lock().expect("fake runtime lock");
+
+        let container = Self::find(&mut state, container_id.as_str())?;
+
+        if container.state != ContainerStateStatusEnum::RUNNING {
+            return Ok(());
+        }
+
+        container.state = ContainerStateStatusEnum::EXITED;
+        container.exit_code = Some(0);
+        container.ports = vec![];
+
+        let container = container.clone();
+        drop(state);
+
+        self.send_event("die", &container);
+
+        Ok(())
+    }
+
+    async fn remove_container(&self, container_id: &ContainerId) -> Result<(), ::anyhow::Error> {
+        let mut state = self.state.lock().expect("fake runtime lock");
+
+        let name = match state.containers.values().find(|c| c.id == container_id.as_str() || c.name == container_id.as_str()) {
+            Some(s) => s.name.clone(),
+            None => return Ok(()),
+        };
+
+        let container = state.containers.remove(&name).expect("container exists");
+        drop(state);
+
+        self.send_event("destroy", &container);
+
+        Ok(())
+    }
+
+    async fn remove_image(&self, image: &str) -> Result<bool, ::anyhow::Error> {
+        let mut state = self.state.lock().expect("fake runtime lock");
+
+        if state.containers.values().any(|c| c.config.image.as_deref() == Some(image)) {
+            return Ok(false);
+        }
+
+        state.images.remove(image);
+
+        Ok(true)
+    }
+
+    async fn inspect_container(&self, container_id: &ContainerId) -> Result<ContainerInspectResponse, ::anyhow::Error> {
+        let mut state = self.state.lock().expect("fake runtime lock");
+
+        let container = Self::find(&mut state, container_id.as_str())?;
+
+        Ok(ContainerInspectResponse {
+            id: Some(container.id.clone()),
+            name: Some(format!("/{}", container.name)),
+            state: Some(ContainerState {
+                status: Some(container.state),
+                running: Some(container.state == ContainerStateStatusEnum::RUNNING),
+                exit_code: container.exit_code,
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+    }
+
+    async fn container_logs(&self, container_id: &ContainerId, tail: usize) -> Result<Vec<String>, ::anyhow::Error> {
+        let mut state = self.state.lock().expect("fake runtime lock");
+
+        let container = Self::find(&mut state, container_id.as_str())?;
+        let skip = container.logs.len().saturating_sub(tail);
+
+        Ok(container.logs.iter().skip(skip).cloned().collect())
+    }
+
+    fn events(&self) -> BoxStream<'static, Result<EventMessage, ::anyhow::Error>> {
+        ::futures::stream::unfold(self.events.subscribe(), |mut receiver| async move {
+            match receiver.recv().await {
+                Ok(event) => Some((Ok(event), receiver)),
+                Err(RecvError::Lagged(n)) => Some((Err(anyhow!("missed {} events", n)), receiver)),
+                Err(RecvError::Closed) => None,
+            }
+        }).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use crate::brain::brain_action::{BrainAction, ContainerStart};
+    use crate::container_manager::gc::GcOptions;
+    use crate::container_manager::world::WorldContainer;
+    use crate::docker::docker_action_executer::DockerActionExecuter;
+    use crate::docker::docker_world_builder::build_world_from_docker;
+    use crate::kv_container::KV;
+    use super::*;
+
+    #[tokio::test]
+    async fn reconcile_against_the_fake_runtime() {
+        let runtime = FakeContainerRuntime::new();
+        let kv = KV::new();
+        let executer = DockerActionExecuter::new(kv.clone(), Arc::new(runtime.clone()), 4);
+
+        let container = WorldContainer {
+            name: "web".to_string(),
+            image: "web:1".to_string(),
+            container_ports: vec![80],
+            ..Default::default()
+        };
+
+        executer.execute(&[BrainAction::ContainersStart(vec![ContainerStart::new_from_world_container(&container)])]).await.expect("started");
+
+        let world = build_world_from_docker(&runtime, &kv).await.expect("world");
+        assert_eq!(1, world.get_containers().len());
+        assert_eq!(container.get_identifier(), world.get_containers()[0].get_identifier());
+        assert_eq!(Some(container.compute_spec_hash()), world.get_containers()[0].spec_hash);
+
+        let container_id = world.get_containers()[0].container_id.clone().expect("container id");
+        runtime.exit_container(container_id.as_str(), 1, vec!["boom".to_string()]).expect("exited");
+
+        assert!(build_world_from_docker(&runtime, &kv).await.expect("world").get_containers().is_empty());
+
+        executer.collect_exited_containers().await.expect("collected");
+        let crash_loop = kv.get_crash_loops().await.remove(&container.get_identifier()).expect("crash loop");
+        assert_eq!(Some(1), crash_loop.last_exit.exit_code);
+        assert_eq!(vec!["boom".to_string()], crash_loop.last_exit.logs);
+
+        executer.collect_garbage(&GcOptions { retention_s: 0, keep_exited: 0 }, &HashSet::new()).await.expect("gc");
+        assert!(runtime.list_containers().await.expect("containers").is_empty());
+        assert!(runtime.get_images().is_empty());
+
+        // an image that was there before is not removed with the containers using it.
+        runtime.pull_image("db:1").await.expect("pulled");
+        let container = WorldContainer { image: "db:1".to_string(), ..container };
+        executer.execute(&[BrainAction::ContainersStart(vec![ContainerStart::new_from_world_container(&container)])]).await.expect("started");
+        assert!(kv.get_pulled_images().await.is_empty());
+    }
+}
@@ -0,0 +1,64 @@
+use std::fmt::Debug;
+use std::sync::Arc;
+use async_trait::async_trait;
+use bollard::container::Config;
+use bollard::models::{ContainerInspectResponse, ContainerSummary, EventMessage};
+use futures::stream::BoxStream;
+use easyharun_lib::container_labels::{is_in_namespace, DEFAULT_NAMESPACE};
+use easyharun_lib::ContainerId;
+
+pub mod fake_runtime;
+pub mod namespace_lock;
+pub mod namespaced_runtime;
+
+// everything easyharun needs from a container engine.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ImagePull {
+    // the image was not there before, easyharun owns it.
+    Pulled,
+    // someone else put the image there, it may have been updated.
+    AlreadyPresent,
+}
+
+// the docker api models are the common language, other engines map onto them.
+#[async_trait]
+pub trait ContainerRuntime: Debug + Send + Sync {
+    // all containers, including the stopped ones.
+    async fn list_containers(&self) -> Result<Vec<ContainerSummary>, ::anyhow::Error>;
+
+    // engines that can filter on labels do it on their side.
+    async fn list_containers_in_namespace(&self, namespace: &str) -> Result<Vec<ContainerSummary>, ::anyhow::Error> {
+        Ok(self.list_containers().await?.into_iter().filter(|c| is_container_in_namespace(c, namespace)).collect())
+    }
+
+    async fn pull_image(&self, image: &str) -> Result<ImagePull, ::anyhow::Error>;
+
+    async fn create_container(&self, name: &str, config: Config<String>) -> Result<(), ::anyhow::Error>;
+
+    async fn start_container(&self, name: &str) -> Result<(), ::anyhow::Error>;
+
+    // succeeds if the container is stopped already.
+    async fn stop_container(&self, container_id: &ContainerId, stop_grace_period_s: u32) -> Result<(), ::anyhow::Error>;
+
+    // succeeds if the container is gone already.
+    async fn remove_container(&self, container_id: &ContainerId) -> Result<(), ::anyhow::Error>;
+
+    // false if the image is still used by a container.
+    async fn remove_image(&self, image: &str) -> Result<bool, ::anyhow::Error>;
+
+    async fn inspect_container(&self, container_id: &ContainerId) -> Result<ContainerInspectResponse, ::anyhow::Error>;
+
+    // the last lines the container logged, oldest first.
+    async fn container_logs(&self, container_id: &ContainerId, tail: usize) -> Result<Vec<String>, ::anyhow::Error>;
+
+    fn events(&self) -> BoxStream<'static, Result<EventMessage, ::anyhow::Error>>;
+}
+
+pub type ContainerRuntimeRef = Arc<dyn ContainerRuntime>;
+
+pub fn is_container_in_namespace(container: &ContainerSummary, namespace: &str) -> bool {
+    match container.labels.as_ref() {
+        Some(labels) => is_in_namespace(labels, namespace),
+        None => namespace == DEFAULT_NAMESPACE,
+    }
+}
@@ -0,0 +1,70 @@
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, Context};
+
+// two instances managing the same namespace would remove each other's containers.
+// the lock is released by the os when the process exits, a stale lock file does no harm.
+// it is a file lock, it only keeps apart the instances of one host. the runtime of a tcp:// endpoint can be shared by several hosts.
+#[derive(Debug)]
+pub struct NamespaceLock {
+    path: PathBuf,
+    // the lock lives as long as the file is open.
+    file: File,
+}
+
+impl NamespaceLock {
+    pub fn acquire(lock_dir: &Path, namespace: &str) -> Result<Self, ::anyhow::Error> {
+        let path = lock_dir.join(format!("easyharun-{}.lock", namespace));
+
+        // not truncated on open, the file holds the pid of the instance owning the lock.
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path)
+            .context(format!("could not open lock file {:?}", path))?;
+
+        match file.try_lock() {
+            Ok(()) => {},
+            Err(TryLockError::WouldBlock) => {
+                let mut pid = String::new();
+                file.read_to_string(&mut pid).context(format!("could not read lock file {:?}", path))?;
+
+                return Err(anyhow!("namespace {} is already managed by another easyharun (pid {}), lock file {:?}", namespace, pid.trim(), path));
+            },
+            Err(TryLockError::Error(e)) => return Err(e).context(format!("could not lock {:?}", path)),
+        };
+
+        file.set_len(0).context(format!("could not truncate lock file {:?}", path))?;
+        file.seek(SeekFrom::Start(0)).context(format!("could not write lock file {:?}", path))?;
+        write!(file, "{}", ::std::process::id()).context(format!("could not write lock file {:?}", path))?;
+
+        Ok(Self {
+            path,
+            file,
+        })
+    }
+
+    pub fn get_path(&self) -> &Path {
+        &self.path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_namespace_can_only_be_locked_once() {
+        let dir = ::std::env::temp_dir().join(format!("easyharun-lock-{}", uuid::Uuid::new_v4()));
+        ::std::fs::create_dir_all(&dir).expect("lock dir");
+
+        let lock = NamespaceLock::acquire(&dir, "staging").expect("locked");
+        let other = NamespaceLock::acquire(&dir, "staging").expect_err("already locked");
+        assert!(other.to_string().contains(&format!("pid {}", ::std::process::id())));
+
+        NamespaceLock::acquire(&dir, "production").expect("other namespace");
+
+        drop(lock);
+        NamespaceLock::acquire(&dir, "staging").expect("released");
+
+        ::std::fs::remove_dir_all(&dir).expect("removed");
+    }
+}
@@ -0,0 +1,153 @@
+use async_trait::async_trait;
+use bollard::container::Config;
+use bollard::models::{ContainerInspectResponse, ContainerSummary, EventMessage, EventMessageTypeEnum};
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use easyharun_lib::container_labels::{is_in_namespace, LABEL_NAMESPACE};
+use easyharun_lib::ContainerId;
+use crate::container_runtime::{ContainerRuntime, ContainerRuntimeRef, ImagePull};
+
+// scopes a runtime to the containers of one easyharun instance.
+// created containers get the namespace label, listings and container events only show the namespace.
+#[derive(Debug, Clone)]
+pub struct NamespacedContainerRuntime {
+    inner: ContainerRuntimeRef,
+    namespace: String,
+}
+
+impl NamespacedContainerRuntime {
+    pub fn new(inner: ContainerRuntimeRef, namespace: &str) -> Self {
+        Self {
+            inner,
+            namespace: namespace.to_string(),
+        }
+    }
+
+    pub fn get_namespace(&self) -> &str {
+        &self.namespace
+    }
+}
+
+#[async_trait]
+impl ContainerRuntime for NamespacedContainerRuntime {
+    async fn list_containers(&self) -> Result<Vec<ContainerSummary>, ::anyhow::Error> {
+        self.inner.list_containers_in_namespace(&self.namespace).await
+    }
+
+    async fn list_containers_in_namespace(&self, namespace: &str) -> Result<Vec<ContainerSummary>, ::anyhow::Error> {
+        match namespace == self.namespace {
+            true => self.list_containers().await,
+            false => Ok(vec![]),
+        }
+    }
+
+    async fn pull_image(&self, image: &str) -> Result<ImagePull, ::anyhow::Error> {
+        self.inner.pull_image(image).await
+    }
+
+    async fn create_container(&self, name: &str, mut config: Config<String>) -> Result<(), ::anyhow::Error> {
+        config.labels.get_or_insert_with(Default::default).insert(LABEL_NAMESPACE.to_string(), self.namespace.clone());
+
+        self.inner.create_container(name, config).await
+    }
+
+    async fn start_container(&self, name: &str) -> Result<(), ::anyhow::Error> {
+        self.inner.start_container(name).await
+    }
+
+    async fn stop_container(&self, container_id: &ContainerId, stop_grace_period_s: u32) -> Result<(), ::anyhow::Error> {
+        self.inner.stop_container(container_id, stop_grace_period_s).await
+    }
+
+    async fn remove_container(&self, container_id: &ContainerId) -> Result<(), ::anyhow::Error> {
+        self.inner.remove_container(container_id).await
+    }
+
+    // images are shared between namespaces, the runtime refuses to remove an image another namespace still uses.
+    async fn remove_image(&self, image: &str) -> Result<bool, ::anyhow::Error> {
+        self.inner.remove_image(image).await
+    }
+
+    async fn inspect_container(&self, container_id: &ContainerId) -> Result<ContainerInspectResponse, ::anyhow::Error> {
+        self.inner.inspect_container(container_id).await
+    }
+
+    async fn container_logs(&self, container_id: &ContainerId, tail: usize) -> Result<Vec<String>, ::anyhow::Error> {
+        self.inner.container_logs(container_id, tail).await
+    }
+
+    fn events(&self) -> BoxStream<'static, Result<EventMessage, ::anyhow::Error>> {
+        let namespace = self.namespace.clone();
+
+        // a container event carries the labels of the container as attributes.
+        self.inner.events()
+            .filter(move |event| {
+                let in_namespace = match event {
+                    Ok(e) if e.typ == Some(EventMessageTypeEnum::CONTAINER) => e.actor.as_ref()
+                        .and_then(|a| a.attributes.as_ref())
+                        .map(|attributes| is_in_namespace(attributes, &namespace))
+                        .unwrap_or(true),
+                    _ => true,
+                };
+
+                ::futures::future::ready(in_namespace)
+            })
+            .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use easyharun_lib::container_labels::DEFAULT_NAMESPACE;
+    use crate::brain::brain_action::{BrainAction, ContainerStart};
+    use crate::container_manager::world::WorldContainer;
+    use crate::container_runtime::fake_runtime::FakeContainerRuntime;
+    use crate::docker::docker_action_executer::DockerActionExecuter;
+    use crate::docker::docker_world_builder::build_world_from_docker;
+    use crate::kv_container::KV;
+    use super::*;
+
+    #[tokio::test]
+    async fn namespaces_only_see_their_own_containers() {
+        let fake_runtime = FakeContainerRuntime::new();
+        let runtime: ContainerRuntimeRef = Arc::new(fake_runtime.clone());
+
+        // created before namespaces existed, it belongs to the default namespace.
+        fake_runtime.pull_image("web:0").await.expect("pulled");
+        fake_runtime.create_container("legacy", Config {
+            image: Some("web:0".to_string()),
+            labels: Some(HashMap::from([("easyharun".to_string(), "2".to_string())])),
+            ..Default::default()
+        }).await.expect("created");
+
+        let container = WorldContainer {
+            name: "web".to_string(),
+            image: "web:1".to_string(),
+            container_ports: vec![80],
+            ..Default::default()
+        };
+
+        for namespace in ["staging", "production"] {
+            let runtime = NamespacedContainerRuntime::new(runtime.clone(), namespace);
+            let executer = DockerActionExecuter::new(KV::new(), Arc::new(runtime), 4);
+
+            executer.execute(&[BrainAction::ContainersStart(vec![ContainerStart::new_from_world_container(&container)])]).await.expect("started");
+        }
+
+        let staging = NamespacedContainerRuntime::new(runtime.clone(), "staging");
+        let world = build_world_from_docker(&staging, &KV::new()).await.expect("world");
+        assert_eq!(1, world.get_containers().len());
+        assert_eq!(container.get_identifier(), world.get_containers()[0].get_identifier());
+
+        let containers = staging.list_containers().await.expect("containers");
+        assert_eq!(Some("staging"), containers[0].labels.as_ref().and_then(|l| l.get(LABEL_NAMESPACE)).map(|s| s.as_str()));
+
+        let default = NamespacedContainerRuntime::new(runtime.clone(), DEFAULT_NAMESPACE);
+        let containers = default.list_containers().await.expect("containers");
+        assert_eq!(vec![Some("/legacy".to_string())], containers.iter().map(|c| c.names.as_ref().map(|n| n[0].clone())).collect::<Vec<_>>());
+
+        assert_eq!(3, runtime.list_containers().await.expect("containers").len());
+    }
+}
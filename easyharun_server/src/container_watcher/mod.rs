@@ -0,0 +1,8 @@
+pub mod watcher;
+pub mod watched_runtime;
+
+// the snapshot of easyharun containers changed, every change gets a new generation.
+#[derive(Clone, Debug)]
+pub struct ContainersChanged {
+    pub generation: u64,
+}
@@ -0,0 +1,206 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use async_trait::async_trait;
+use bollard::container::Config;
+use bollard::models::{ContainerInspectResponse, ContainerSummary, EventMessage, Port};
+use futures::stream::BoxStream;
+use tokio::sync::{RwLock, watch};
+use tokio::task::JoinHandle;
+use easyact::ActorStateHandle;
+use easyharun_lib::ContainerId;
+use crate::container_runtime::{ContainerRuntime, ContainerRuntimeRef, ImagePull};
+use crate::container_watcher::ContainersChanged;
+
+// serves list_containers from a snapshot the ContainerWatcher keeps up to date,
+// everything else goes straight to the runtime.
+#[derive(Debug, Clone)]
+pub struct WatchedContainerRuntime {
+    inner: ContainerRuntimeRef,
+    // None until the first listing and after easyharun changed containers itself.
+    snapshot: Arc<RwLock<Option<Vec<ContainerSummary>>>>,
+    // the snapshot is only used while the events stream is connected.
+    watching: Arc<AtomicBool>,
+    generation: Arc<watch::Sender<u64>>,
+}
+
+impl WatchedContainerRuntime {
+    pub fn new(inner: ContainerRuntimeRef) -> Self {
+        Self {
+            inner,
+            snapshot: Arc::new(RwLock::new(None)),
+            watching: Arc::new(AtomicBool::new(false)),
+            generation: Arc::new(watch::channel(0).0),
+        }
+    }
+
+    pub fn get_inner(&self) -> ContainerRuntimeRef {
+        self.inner.clone()
+    }
+
+    pub fn set_watching(&self, watching: bool) {
+        self.watching.store(watching, Ordering::SeqCst);
+    }
+
+    pub fn get_generation(&self) -> u64 {
+        *self.generation.borrow()
+    }
+
+    pub async fn invalidate(&self) {
+        *self.snapshot.write().await = None;
+    }
+
+    // lists the containers again, true if something changed since the last snapshot.
+    pub async fn refresh(&self) -> Result<bool, ::anyhow::Error> {
+        let containers = self.inner.list_containers().await?;
+
+        let mut snapshot = self.snapshot.write().await;
+
+        let changed = match snapshot.as_ref() {
+            Some(s) => fingerprint(s) != fingerprint(&containers),
+            None => true,
+        };
+
+        *snapshot = Some(containers);
+        drop(snapshot);
+
+        if changed {
+            self.generation.send_modify(|generation| *generation += 1);
+        }
+
+        Ok(changed)
+    }
+
+    // sends a message to the actor whenever the snapshot changes.
+    pub fn subscribe<MSG, F>(&self, actor: ActorStateHandle<MSG>, to_msg: F) -> JoinHandle<()>
+        where MSG: Send + Sync + Unpin + 'static, F: Fn(ContainersChanged) -> MSG + Send + 'static
+    {
+        let mut receiver = self.generation.subscribe();
+
+        ::tokio::spawn(async move {
+            loop {
+                if receiver.changed().await.is_err() {
+                    return;
+                }
+
+                let generation = *receiver.borrow_and_update();
+
+                if actor.send(to_msg(ContainersChanged { generation })).await.is_err() {
+                    return;
+                }
+            }
+        })
+    }
+}
+
+// id, state, health and ports.
+type ContainerFingerprint<'a> = (Option<&'a String>, Option<&'a String>, Option<&'a str>, Option<&'a Vec<Port>>);
+
+// the status text ("Up 3 minutes (healthy)") changes all the time, only the health at its end is a change of the container.
+fn get_health(status: Option<&String>) -> Option<&str> {
+    let status = status?.trim_end().strip_suffix(')')?;
+    status.rfind('(').map(|i| &status[i + 1..])
+}
+
+fn fingerprint(containers: &[ContainerSummary]) -> Vec<ContainerFingerprint<'_>> {
+    containers.iter().map(|c| (c.id.as_ref(), c.state.as_ref(), get_health(c.status.as_ref()), c.ports.as_ref())).collect()
+}
+
+#[async_trait]
+impl ContainerRuntime for WatchedContainerRuntime {
+    async fn list_containers(&self) -> Result<Vec<ContainerSummary>, ::anyhow::Error> {
+        if self.watching.load(Ordering::SeqCst) {
+            if let Some(containers) = self.snapshot.read().await.as_ref() {
+                return Ok(containers.clone());
+            }
+        }
+
+        self.refresh().await?;
+
+        Ok(self.snapshot.read().await.clone().unwrap_or_default())
+    }
+
+    async fn pull_image(&self, image: &str) -> Result<ImagePull, ::anyhow::Error> {
+        self.inner.pull_image(image).await
+    }
+
+    async fn create_container(&self, name: &str, config: Config<String>) -> Result<(), ::anyhow::Error> {
+        let result = self.inner.create_container(name, config).await;
+        self.invalidate().await;
+        result
+    }
+
+    async fn start_container(&self, name: &str) -> Result<(), ::anyhow::Error> {
+        let result = self.inner.start_container(name).await;
+        self.invalidate().await;
+        result
+    }
+
+    async fn stop_container(&self, container_id: &ContainerId, stop_grace_period_s: u32) -> Result<(), ::anyhow::Error> {
+        let result = self.inner.stop_container(container_id, stop_grace_period_s).await;
+        self.invalidate().await;
+        result
+    }
+
+    async fn remove_container(&self, container_id: &ContainerId) -> Result<(), ::anyhow::Error> {
+        let result = self.inner.remove_container(container_id).await;
+        self.invalidate().await;
+        result
+    }
+
+    async fn remove_image(&self, image: &str) -> Result<bool, ::anyhow::Error> {
+        self.inner.remove_image(image).await
+    }
+
+    async fn inspect_container(&self, container_id: &ContainerId) -> Result<ContainerInspectResponse, ::anyhow::Error> {
+        self.inner.inspect_container(container_id).await
+    }
+
+    async fn container_logs(&self, container_id: &ContainerId, tail: usize) -> Result<Vec<String>, ::anyhow::Error> {
+        self.inner.container_logs(container_id, tail).await
+    }
+
+    fn events(&self) -> BoxStream<'static, Result<EventMessage, ::anyhow::Error>> {
+        self.inner.events()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::brain::brain_action::{BrainAction, ContainerStart};
+    use crate::container_manager::world::WorldContainer;
+    use crate::container_runtime::fake_runtime::FakeContainerRuntime;
+    use crate::docker::docker_action_executer::DockerActionExecuter;
+    use crate::kv_container::KV;
+    use super::*;
+
+    #[tokio::test]
+    async fn snapshot_follows_refreshes_and_own_changes() {
+        let fake = FakeContainerRuntime::new();
+        let runtime = WatchedContainerRuntime::new(Arc::new(fake.clone()));
+        runtime.set_watching(true);
+
+        assert!(runtime.list_containers().await.expect("containers").is_empty());
+
+        // starting a container through the runtime is visible right away.
+        let executer = DockerActionExecuter::new(KV::new(), Arc::new(runtime.clone()), 1);
+        let container = WorldContainer { name: "web".to_string(), image: "web:1".to_string(), ..Default::default() };
+        executer.execute(&[BrainAction::ContainersStart(vec![ContainerStart::new_from_world_container(&container)])]).await.expect("started");
+
+        let containers = runtime.list_containers().await.expect("containers");
+        assert_eq!(Some("running".to_string()), containers[0].state);
+
+        // changes behind its back show up once the watcher refreshes.
+        fake.exit_container(containers[0].id.as_deref().unwrap(), 1, vec![]).expect("exited");
+        assert_eq!(Some("running".to_string()), runtime.list_containers().await.expect("containers")[0].state);
+
+        let generation = runtime.get_generation();
+        assert!(runtime.refresh().await.expect("refreshed"));
+        assert!(!runtime.refresh().await.expect("refreshed"));
+        assert_eq!(generation + 1, runtime.get_generation());
+        assert_eq!(Some("exited".to_string()), runtime.list_containers().await.expect("containers")[0].state);
+
+        assert_eq!(Some("healthy"), get_health(Some(&"Up 3 minutes (healthy)".to_string())));
+        assert_eq!(Some("health: starting"), get_health(Some(&"Up 1 second (health: starting)".to_string())));
+        assert_eq!(None, get_health(Some(&"Up 3 minutes".to_string())));
+    }
+}
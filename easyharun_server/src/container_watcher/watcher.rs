@@ -0,0 +1,143 @@
+use std::pin::Pin;
+use std::time::Duration;
+use anyhow::Error;
+use async_trait::async_trait;
+use bollard::models::{EventMessage, EventMessageTypeEnum};
+use futures::StreamExt;
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+use easyact::{Actor, ActorState, ActorStateHandle};
+use crate::container_watcher::watched_runtime::WatchedContainerRuntime;
+
+// a full listing now and then, in case an event got lost.
+const RESYNC_INTERVAL: Duration = Duration::from_secs(30);
+const EVENTS_RECONNECT_DELAY: Duration = Duration::from_secs(5);
+// a stream that did not fail within this time is connected.
+const EVENTS_CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug)]
+pub enum ContainerWatcherMsg {
+    Event(EventMessage),
+    EventsDisconnected(String),
+}
+
+// follows the events of the container runtime and keeps the snapshot of the WatchedContainerRuntime up to date.
+#[derive(Debug)]
+pub struct ContainerWatcher {
+    actor_state: ActorState<ContainerWatcherMsg>,
+    runtime: WatchedContainerRuntime,
+    events: JoinHandle<()>,
+}
+
+impl ContainerWatcher {
+    pub fn new(actor_state: ActorState<ContainerWatcherMsg>, runtime: WatchedContainerRuntime) -> Self {
+        let events = Self::spawn_events(actor_state.create_handle(), runtime.clone());
+
+        Self {
+            actor_state,
+            runtime,
+            events,
+        }
+    }
+
+    fn spawn_events(actor: ActorStateHandle<ContainerWatcherMsg>, runtime: WatchedContainerRuntime) -> JoinHandle<()> {
+        ::tokio::spawn(async move {
+            loop {
+                let mut events = runtime.get_inner().events().peekable();
+
+                // the stream only connects on its first poll, until it delivered an event or stayed open the snapshot is not used.
+                let connected = match ::tokio::time::timeout(EVENTS_CONNECT_TIMEOUT, Pin::new(&mut events).peek()).await {
+                    Ok(Some(Ok(_))) | Err(_) => true,
+                    Ok(_) => false,
+                };
+
+                if connected {
+                    // events that happened while we were not connected are picked up by a full listing.
+                    runtime.set_watching(true);
+                    runtime.invalidate().await;
+                }
+
+                let reason = loop {
+                    match events.next().await {
+                        Some(Ok(event)) => {
+                            if actor.send(ContainerWatcherMsg::Event(event)).await.is_err() {
+                                return;
+                            }
+                        },
+                        Some(Err(e)) => break format!("{:#}", e),
+                        None => break "the events stream ended".to_string(),
+                    };
+                };
+
+                runtime.set_watching(false);
+
+                if actor.send(ContainerWatcherMsg::EventsDisconnected(reason)).await.is_err() {
+                    return;
+                }
+
+                ::tokio::time::sleep(EVENTS_RECONNECT_DELAY).await;
+            }
+        })
+    }
+
+    async fn on_event(&self, event: EventMessage) -> Result<(), ::anyhow::Error> {
+        if event.typ != Some(EventMessageTypeEnum::CONTAINER) {
+            return Ok(());
+        }
+
+        let is_easyharun = event.actor.as_ref()
+            .and_then(|a| a.attributes.as_ref())
+            .map(|a| a.contains_key("easyharun"))
+            .unwrap_or(false);
+
+        let action = event.action.unwrap_or_default();
+
+        // exec_create, exec_start, ... do not change the container.
+        if !is_easyharun || action.starts_with("exec_") {
+            return Ok(());
+        }
+
+        debug!("container event {}", action);
+
+        self.runtime.refresh().await?;
+
+        Ok(())
+    }
+}
+
+impl Drop for ContainerWatcher {
+    fn drop(&mut self) {
+        self.events.abort();
+    }
+}
+
+#[async_trait]
+impl Actor for ContainerWatcher {
+    type MSG = ContainerWatcherMsg;
+
+    fn get_actor_state(&mut self) -> &mut ActorState<Self::MSG> {
+        &mut self.actor_state
+    }
+
+    fn timer_duration(&self) -> Option<Duration> {
+        Some(RESYNC_INTERVAL)
+    }
+
+    async fn on_timer(&mut self) -> Result<(), Error> {
+        if self.runtime.refresh().await? {
+            debug!("resync found changed containers");
+        }
+
+        Ok(())
+    }
+
+    async fn on_msg(&mut self, msg: Self::MSG) -> Result<(), Error> {
+        match msg {
+            ContainerWatcherMsg::Event(event) => self.on_event(event).await,
+            ContainerWatcherMsg::EventsDisconnected(reason) => {
+                warn!("lost the container events, listing containers on every tick until they are back: {}", reason);
+                Ok(())
+            }
+        }
+    }
+}
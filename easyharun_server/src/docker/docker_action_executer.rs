@@ -1,76 +1,110 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::time::Instant;
 use anyhow::Context;
-use bollard::container::{Config, CreateContainerOptions, StartContainerOptions};
-use bollard::Docker;
-use bollard::image::CreateImageOptions;
-use bollard::models::{HostConfig, PortBinding};
-use tracing::{debug, info};
+use bollard::container::Config;
+use bollard::models::{ContainerSummary, HostConfig, PortBinding, ResourcesUlimits};
+use tracing::{debug, info, warn};
 use uuid::Uuid;
+use easyharun_lib::config::DEFAULT_STOP_GRACE_PERIOD_S;
+use easyharun_lib::container_labels::{is_managed_by_easyharun, label_schema_version, ContainerLabels, LABEL_SCHEMA_VERSION};
+use easyharun_lib::ContainerId;
 use crate::brain::brain_action::{BrainAction, ContainerStart, ContainerStop};
-use crate::docker::docker_connection::docker_create_connection;
+use crate::kv_container::state::{ContainerExit, CRASH_LOOP_LOG_LINES};
+use crate::container_manager::gc::{select_exited_containers_to_remove, ExitedContainer, GcOptions};
+use crate::container_runtime::{ContainerRuntimeRef, ImagePull};
+use crate::docker::docker_world_builder::{build_world_container, read_container_identity, world_container_from_labels};
 use crate::kv_container::KV;
 use futures::StreamExt;
-use serde_json::json;
+
+pub const DEFAULT_MAX_PARALLEL_ACTIONS: usize = 4;
 
 pub struct DockerActionExecuter {
     kv: KV,
+    runtime: ContainerRuntimeRef,
+    // starts and stops that run at the same time.
+    max_parallel_actions: usize,
 }
 
+enum DockerAction<'a> {
+    Start(&'a ContainerStart),
+    Stop(&'a ContainerStop),
+}
 
 impl DockerActionExecuter {
-    pub fn new(kv: KV) -> Self {
+    pub fn new(kv: KV, runtime: ContainerRuntimeRef, max_parallel_actions: usize) -> Self {
         Self {
-            kv
+            kv,
+            runtime,
+            max_parallel_actions: max_parallel_actions.max(1),
         }
     }
 
-    pub async fn execute(&self, action: &BrainAction) -> Result<(), ::anyhow::Error> {
-        match action {
-            BrainAction::ContainersStart(c) => return self.execute_containers_start(c).await,
-            BrainAction::ContainersStop(c) => return self.execute_containers_stop(c).await,
-            BrainAction::NoOp => Ok(()),
+    // all starts and stops of a plan are independent, so they run concurrently.
+    // a failing one does not stop the others, all errors are returned.
+    pub async fn execute(&self, actions: &[BrainAction]) -> Result<(), Vec<::anyhow::Error>> {
+        let docker_actions = actions.iter().flat_map(|action| match action {
+            BrainAction::ContainersStart(c) => c.iter().map(DockerAction::Start).collect::<Vec<_>>(),
+            BrainAction::ContainersStop(c) => c.iter().map(DockerAction::Stop).collect::<Vec<_>>(),
+            BrainAction::Wait(_) => vec![],
+        }).collect::<Vec<_>>();
+
+        if docker_actions.is_empty() {
+            return Ok(());
         }
-    }
 
-    async fn execute_containers_start(&self, action: &Vec<ContainerStart>) -> Result<(), ::anyhow::Error> {
-        let docker = docker_create_connection()?;
+        let futures = docker_actions.into_iter()
+            .map(|action| self.execute_docker_action(action))
+            .collect::<Vec<_>>();
+
+        let errors = ::futures::stream::iter(futures)
+            .buffer_unordered(self.max_parallel_actions)
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .filter_map(|result| result.err())
+            .collect::<Vec<_>>();
 
-        for container_start in action.iter() {
-            self.execute_container_start(&docker, container_start).await.context("starting docker container")?
+        if !errors.is_empty() {
+            return Err(errors);
         }
 
         Ok(())
     }
 
-    async fn execute_container_start(&self, docker: &Docker, container_start: &ContainerStart) -> Result<(), ::anyhow::Error> {
+    async fn execute_docker_action(&self, action: DockerAction<'_>) -> Result<(), ::anyhow::Error> {
+        match action {
+            DockerAction::Start(c) => self.execute_container_start(c).await
+                .context(format!("starting container {} replica {}", c.container_world.name, c.container_world.replica_id)),
+            DockerAction::Stop(c) => self.execute_container_stop(c).await
+                .context(format!("stopping container {:?}", c.id)),
+        }
+    }
+
+    async fn execute_container_start(&self, container_start: &ContainerStart) -> Result<(), ::anyhow::Error> {
         debug!("execute_containers_start");
 
         let container = &container_start.container_world;
 
-        let options = CreateImageOptions{
-            from_image: container.image.clone(),
-            ..Default::default()
-        };
-
-        let mut create_image = docker.create_image(Some(options), None, None);
-
-        while let Some(v) = create_image.next().await {
-            debug!("getting image ...");
+        // the gc only removes the images easyharun pulled, not the ones that were there before.
+        if self.runtime.pull_image(&container.image).await? == ImagePull::Pulled {
+            self.kv.add_pulled_image(&container.image).await;
         }
         debug!("got image ...");
 
 
         let name = Uuid::new_v4();
 
+        let runtime = &container.runtime;
+
         let labels = {
             let mut buf = HashMap::new();
-            buf.insert("easyharun".to_string(), "1.0.0".to_string());
-            buf.insert("easyharun_name".to_string(), container.name.to_string());
-            buf.insert("easyharun_image".to_string(), container.image.to_string());
-            buf.insert("easyharun_replica_id".to_string(), container.replica_id.to_string());
-            buf.insert("easyharun_container_ports".to_string(), container.container_ports.iter().map(|x|x.to_string()).collect::<Vec<_>>().join(","));
-            buf.insert("easyharun_health_checks".to_string(), container.health_checks.join(","));
-            buf.insert("easyharun_proxies".to_string(), json!(container.proxies.clone()).to_string());
+
+            // extra labels first, so they can't override ours.
+            for (label, value) in runtime.labels.iter() {
+                buf.insert(label.to_string(), value.to_string());
+            }
+
+            buf.extend(container.get_container_labels().to_labels());
 
             buf
         };
@@ -100,47 +134,319 @@ impl DockerActionExecuter {
         let config = Config {
             image: Some(container.image.clone()),
             labels: Some(labels),
+            env: Some(runtime.env.iter().map(|(k, v)| format!("{}={}", k, v)).collect()),
+            cmd: runtime.command.clone(),
+            entrypoint: runtime.entrypoint.clone(),
+            working_dir: runtime.working_dir.clone(),
+            user: runtime.user.clone(),
             host_config: Some(HostConfig {
                 port_bindings: Some(port_bindings),
+                binds: Some(runtime.volumes.iter().map(|v| v.to_bind()).collect()),
+                memory: runtime.memory_mb.map(|m| (m * 1024 * 1024) as i64),
+                nano_cpus: runtime.cpus.map(|c| (c * 1_000_000_000.0) as i64),
+                ulimits: Some(runtime.ulimits.iter().map(|u| ResourcesUlimits {
+                    name: Some(u.name.clone()),
+                    soft: Some(u.soft),
+                    hard: Some(u.hard),
+                }).collect()),
                 ..Default::default()
             }),
             exposed_ports: Some(exposed_ports),
             ..Default::default()
         };
 
-        let _ = &docker
-            .create_container(
-                Some(CreateContainerOptions {
-                    name: name.to_string(),
-                    platform: None,
-                }),
-                config,
-            )
-            .await?;
+        self.runtime.create_container(&name.to_string(), config).await?;
 
         debug!("execute_containers_start");
-        let _ = &docker
-            .start_container(&name.to_string(), None::<StartContainerOptions<String>>)
-            .await?;
+        self.runtime.start_container(&name.to_string()).await?;
 
         Ok(())
     }
 
 
-    async fn execute_containers_stop(&self, action: &Vec<ContainerStop>) -> Result<(), ::anyhow::Error> {
-        let docker = docker_create_connection()?;
+    async fn execute_container_stop(&self, container: &ContainerStop) -> Result<(), ::anyhow::Error> {
+
+        info!("execute_containers_stop");
+
+        // marked containers are hidden from all worlds, so the proxies start to drain them.
+        // the container itself is stopped by execute_pending_container_stops.
+        self.kv.mark_container_to_be_deleted(&container.id).await;
+
+        Ok(())
+    }
+
+    // containers that exited on their own are recorded as crashes, the gc removes them later.
+    // the replica is started again once its backoff is over, see crash_loop.
+    pub async fn collect_exited_containers(&self) -> Result<(), ::anyhow::Error> {
+        let containers = self.runtime.list_containers().await?;
+
+        for container in containers.iter() {
+            let labels = container.labels.clone().unwrap_or_default();
+
+            if !is_managed_by_easyharun(&labels) {
+                continue;
+            }
 
-        for container_stop in action.iter() {
-            self.execute_container_stop(container_stop).await?
+            if !matches!(container.state.as_deref(), Some("exited") | Some("dead")) {
+                continue;
+            }
+
+            let identity = match read_container_identity(container) {
+                Ok(s) => s,
+                Err(e) => {
+                    warn!("could not read the identity of exited container {:?}. error: {:#?}", container.id, e);
+                    continue;
+                }
+            };
+
+            let container_id = identity.id;
+
+            if self.kv.is_container_marked_to_be_deleted(&container_id).await {
+                continue;
+            }
+
+            if !self.kv.mark_container_exited(&container_id).await {
+                continue;
+            }
+
+            // exited containers do not have ports, so the world container is built from the labels only.
+            let world_container = match ContainerLabels::from_labels(&labels) {
+                Ok(s) => world_container_from_labels(s),
+                Err(e) => {
+                    warn!("could not read the labels of exited container {:?}. error: {:#?}", container_id, e);
+                    continue;
+                }
+            };
+
+            let exit = self.read_container_exit(&container_id).await;
+
+            let crash_loop = self.kv.record_container_exit(&world_container.get_identifier(), &world_container.name, world_container.replica_id, exit).await;
+
+            warn!(
+                "container {} replica {} ({:?}) exited with code {:?}, restart {} in {}s",
+                crash_loop.name,
+                crash_loop.replica_id,
+                container_id,
+                crash_loop.last_exit.exit_code,
+                crash_loop.restarts,
+                crash_loop.backoff_until.saturating_duration_since(crash_loop.exited_at).as_secs()
+            );
         }
 
         Ok(())
     }
 
-    async fn execute_container_stop(&self, container: &ContainerStop) -> Result<(), ::anyhow::Error> {
+    // labels of a container can not be changed, so containers of an older label schema are adopted as they are.
+    // the ones this version can not read anymore are replaced, otherwise they would run next to their replacement.
+    pub async fn adopt_older_label_schemas(&self) -> Result<(), ::anyhow::Error> {
+        let containers = self.runtime.list_containers().await?;
 
-        info!("execute_containers_stop");
-        self.kv.mark_container_to_be_deleted(&container.id).await;
+        for container in containers.iter() {
+            let labels = container.labels.clone().unwrap_or_default();
+
+            let version = match label_schema_version(&labels) {
+                Some(s) if s < LABEL_SCHEMA_VERSION => s,
+                _ => continue,
+            };
+
+            let container_id = match &container.id {
+                Some(s) => ContainerId::new(s.to_string()),
+                None => continue,
+            };
+
+            match ContainerLabels::from_labels(&labels) {
+                Ok(s) => info!("adopting container {:?} of {} replica {} with label schema version {}", container_id, s.name, s.replica_id, version),
+                Err(e) => {
+                    warn!("could not read container {:?} with label schema version {}, replacing it. error: {:#?}", container_id, version, e);
+                    self.kv.mark_container_to_be_deleted(&container_id).await;
+                }
+            };
+        }
+
+        Ok(())
+    }
+
+    // the persisted state may still know containers that were removed while easyharun was not running.
+    pub async fn prune_vanished_containers(&self) -> Result<(), ::anyhow::Error> {
+        let container_ids = self.runtime.list_containers().await?.into_iter()
+            .filter_map(|c| c.id)
+            .map(ContainerId::new)
+            .collect::<HashSet<_>>();
+
+        self.kv.retain_containers(&container_ids).await;
+
+        Ok(())
+    }
+
+    // removes exited containers after the retention and the images no container uses anymore.
+    pub async fn collect_garbage(&self, options: &GcOptions, expected_images: &HashSet<String>) -> Result<(), ::anyhow::Error> {
+        let containers = self.runtime.list_containers().await?;
+
+        let mut exited = vec![];
+
+        for container in containers.iter() {
+            let labels = container.labels.clone().unwrap_or_default();
+
+            if !is_managed_by_easyharun(&labels) {
+                continue;
+            }
+
+            let container_id = match &container.id {
+                Some(s) => ContainerId::new(s.to_string()),
+                None => continue,
+            };
+
+            // noticed by collect_exited_containers first, so the crash is recorded.
+            let exited_at = match self.kv.get_container_exited_at(&container_id).await {
+                Some(s) => s,
+                None => continue,
+            };
+
+            exited.push(ExitedContainer {
+                container_id,
+                name: labels.get("easyharun_name").cloned().unwrap_or_default(),
+                created: container.created.unwrap_or_default(),
+                exited_at,
+            });
+        }
+
+        let mut removed = HashSet::new();
+
+        for container in select_exited_containers_to_remove(exited, options, Instant::now()) {
+            info!("removing exited container {:?} of {}", container.container_id, container.name);
+
+            if let Err(e) = self.runtime.remove_container(&container.container_id).await {
+                warn!("could not remove exited container {:?}. error: {:#?}", container.container_id, e);
+                continue;
+            }
+
+            self.kv.forget_container_exited(&container.container_id).await;
+            removed.insert(container.container_id.as_str().to_string());
+        }
+
+        // images of the containers that are left, easyharun or not.
+        let used_images = containers.iter()
+            .filter(|c| !removed.contains(c.id.as_deref().unwrap_or_default()))
+            .filter_map(|c| c.image.clone())
+            .collect::<HashSet<_>>();
+
+        for image in self.kv.get_pulled_images().await {
+            if used_images.contains(&image) || expected_images.contains(&image) {
+                continue;
+            }
+
+            info!("removing unused image {}", image);
+
+            match self.runtime.remove_image(&image).await {
+                Ok(true) => {},
+                // used by a container we do not know about.
+                Ok(false) => debug!("image {} is still in use", image),
+                Err(e) => {
+                    warn!("could not remove image {}. error: {:#?}", image, e);
+                    continue;
+                }
+            };
+
+            self.kv.forget_pulled_image(&image).await;
+        }
+
+        Ok(())
+    }
+
+    async fn read_container_exit(&self, container_id: &ContainerId) -> ContainerExit {
+        let exit_code = match self.runtime.inspect_container(container_id).await {
+            Ok(s) => s.state.and_then(|s| s.exit_code),
+            Err(e) => {
+                warn!("could not inspect container {:?}. error: {:#?}", container_id, e);
+                None
+            }
+        };
+
+        let logs = match self.runtime.container_logs(container_id, CRASH_LOOP_LOG_LINES).await {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("could not read logs of container {:?}. error: {:#?}", container_id, e);
+                vec![]
+            }
+        };
+
+        ContainerExit {
+            exit_code,
+            logs,
+        }
+    }
+
+    pub async fn execute_pending_container_stops(&self) -> Result<(), ::anyhow::Error> {
+        let container_ids = self.kv.get_containers_marked_to_be_deleted().await;
+
+        if container_ids.is_empty() {
+            return Ok(());
+        }
+
+        let containers = self.runtime.list_containers().await?;
+
+        let mut pending = vec![];
+
+        for container_id in container_ids.iter() {
+            match containers.iter().find(|c| c.id.as_deref() == Some(container_id.as_str())) {
+                Some(s) => pending.push((container_id, s)),
+                None => {
+                    info!("container {:?} is already gone.", container_id);
+                    self.kv.forget_container_to_be_deleted(container_id).await;
+                }
+            };
+        }
+
+        // stopping waits for the grace period, so containers are stopped concurrently.
+        let futures = pending.into_iter()
+            .map(|(container_id, container_summary)| self.execute_pending_container_stop(container_id, container_summary))
+            .collect::<Vec<_>>();
+
+        let results = ::futures::stream::iter(futures)
+            .buffer_unordered(self.max_parallel_actions)
+            .collect::<Vec<_>>()
+            .await;
+
+        // errors are retried on the next tick, the container stays marked.
+        for e in results.into_iter().filter_map(|result| result.err()) {
+            warn!("could not stop container. error: {:#?}", e);
+        }
+
+        Ok(())
+    }
+
+    async fn execute_pending_container_stop(&self, container_id: &ContainerId, container_summary: &ContainerSummary) -> Result<(), ::anyhow::Error> {
+
+        // created containers do not have ports yet, so they can't be part of a proxy.
+        let world_container = build_world_container(container_summary).unwrap_or(None);
+
+        if let Some(world_container) = &world_container {
+            for server_addr in world_container.get_server_addrs() {
+                if self.kv.is_server_addr_used_by_proxy(&server_addr).await {
+                    debug!("container {:?} is still used by a proxy ({}), waiting.", container_id, server_addr);
+                    return Ok(());
+                }
+            }
+        }
+
+        let stop_grace_period_s = match &world_container {
+            Some(s) => s.stop_grace_period_s,
+            None => DEFAULT_STOP_GRACE_PERIOD_S,
+        };
+
+        info!("stopping container {:?}", container_id);
+        self.runtime.stop_container(container_id, stop_grace_period_s).await?;
+
+        info!("removing container {:?}", container_id);
+        self.runtime.remove_container(container_id).await?;
+
+        self.kv.forget_container_to_be_deleted(container_id).await;
+        self.kv.forget_container_health(container_id).await;
+        self.kv.forget_container_exited(container_id).await;
+
+        for server_addr in world_container.iter().flat_map(|c| c.get_server_addrs()) {
+            self.kv.forget_proxy_connection_stats(&server_addr).await;
+        }
 
         Ok(())
     }
@@ -1,7 +1,81 @@
-use anyhow::Context;
-use bollard::Docker;
+use std::str::FromStr;
+use anyhow::{anyhow, Context};
+use bollard::{Docker, API_DEFAULT_VERSION};
 
-pub fn docker_create_connection() -> Result<Docker, ::anyhow::Error> {
-    Docker::connect_with_local_defaults().context("could not connect docker.")
-    // Docker::connect_with_socket_defaults().context("could not connect docker.")
-}
\ No newline at end of file
+const DOCKER_TIMEOUT_S: u64 = 120;
+
+// where the docker compatible api listens.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum RuntimeEndpoint {
+    // DOCKER_HOST or the default docker socket.
+    LocalDefaults,
+    Unix(String),
+    Http(String),
+    // the socket of rootless podman for the current user, or the system socket for root.
+    Podman,
+}
+
+impl FromStr for RuntimeEndpoint {
+    type Err = ::anyhow::Error;
+
+    fn from_str(endpoint: &str) -> Result<Self, Self::Err> {
+        match endpoint {
+            "" | "docker" => return Ok(Self::LocalDefaults),
+            "podman" => return Ok(Self::Podman),
+            _ => {},
+        };
+
+        if let Some(path) = endpoint.strip_prefix("unix://") {
+            return Ok(Self::Unix(path.to_string()));
+        }
+
+        if endpoint.starts_with('/') {
+            return Ok(Self::Unix(endpoint.to_string()));
+        }
+
+        if endpoint.starts_with("tcp://") || endpoint.starts_with("http://") {
+            return Ok(Self::Http(endpoint.to_string()));
+        }
+
+        Err(anyhow!("unknown runtime endpoint \"{}\", expected docker, podman, unix:///path/to.sock or tcp://host:port", endpoint))
+    }
+}
+
+pub fn docker_create_connection(endpoint: &RuntimeEndpoint) -> Result<Docker, ::anyhow::Error> {
+    match endpoint {
+        RuntimeEndpoint::LocalDefaults => Docker::connect_with_local_defaults(),
+        RuntimeEndpoint::Unix(path) => Docker::connect_with_unix(path, DOCKER_TIMEOUT_S, API_DEFAULT_VERSION),
+        RuntimeEndpoint::Http(addr) => Docker::connect_with_http(addr, DOCKER_TIMEOUT_S, API_DEFAULT_VERSION),
+        RuntimeEndpoint::Podman => Docker::connect_with_unix(&podman_socket(), DOCKER_TIMEOUT_S, API_DEFAULT_VERSION),
+    }.context(format!("could not connect to {:?}.", endpoint))
+}
+
+fn podman_socket() -> String {
+    // the owner of /proc/self is the user running easyharun.
+    let uid = {
+        use std::os::unix::fs::MetadataExt;
+        ::std::fs::metadata("/proc/self").map(|m| m.uid()).unwrap_or(0)
+    };
+
+    // root talks to the system podman, XDG_RUNTIME_DIR may still be set by sudo or a login shell.
+    match (uid, ::std::env::var("XDG_RUNTIME_DIR")) {
+        (0, _) => "/run/podman/podman.sock".to_string(),
+        (_, Ok(runtime_dir)) => format!("{}/podman/podman.sock", runtime_dir),
+        (uid, Err(_)) => format!("/run/user/{}/podman/podman.sock", uid),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_runtime_endpoints() {
+        assert_eq!(RuntimeEndpoint::LocalDefaults, "docker".parse().unwrap());
+        assert_eq!(RuntimeEndpoint::Podman, "podman".parse().unwrap());
+        assert_eq!(RuntimeEndpoint::Unix("/run/user/1000/podman/podman.sock".to_string()), "unix:///run/user/1000/podman/podman.sock".parse().unwrap());
+        assert_eq!(RuntimeEndpoint::Unix("/var/run/docker.sock".to_string()), "/var/run/docker.sock".parse().unwrap());
+        assert_eq!(RuntimeEndpoint::Http("tcp://10.0.0.1:2375".to_string()), "tcp://10.0.0.1:2375".parse().unwrap());
+        assert!("ftp://nope".parse::<RuntimeEndpoint>().is_err());
+    }
+}
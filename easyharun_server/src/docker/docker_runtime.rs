@@ -0,0 +1,272 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use anyhow::Context;
+use async_trait::async_trait;
+use bollard::container::{Config, CreateContainerOptions, InspectContainerOptions, ListContainersOptions, LogsOptions, RemoveContainerOptions, StartContainerOptions, StopContainerOptions};
+use bollard::Docker;
+use bollard::errors::Error as DockerError;
+use bollard::image::CreateImageOptions;
+use bollard::models::{ContainerInspectResponse, ContainerSummary, EventMessage};
+use bollard::system::EventsOptions;
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use tokio::sync::OnceCell;
+use tracing::{debug, info, warn};
+use easyharun_lib::container_labels::{DEFAULT_NAMESPACE, LABEL_NAMESPACE};
+use easyharun_lib::ContainerId;
+use crate::container_runtime::{is_container_in_namespace, ContainerRuntime, ImagePull};
+use crate::docker::docker_connection::{docker_create_connection, RuntimeEndpoint};
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum DockerFlavor {
+    Docker,
+    // the docker compatible api of podman.
+    Podman,
+}
+
+#[derive(Debug, Clone)]
+pub struct DockerRuntime {
+    docker: Docker,
+    endpoint: RuntimeEndpoint,
+    // detected once the daemon answers, it may come up after easyharun.
+    flavor: Arc<OnceCell<DockerFlavor>>,
+}
+
+impl DockerRuntime {
+    pub fn connect(endpoint: &RuntimeEndpoint) -> Result<Self, ::anyhow::Error> {
+        Ok(Self {
+            docker: docker_create_connection(endpoint)?,
+            endpoint: endpoint.clone(),
+            flavor: Arc::new(OnceCell::new()),
+        })
+    }
+
+    // docker until the version could be read, a failed detection is retried with the next call.
+    pub async fn get_flavor(&self) -> DockerFlavor {
+        let flavor = self.flavor.get_or_try_init(|| async {
+            let version = self.docker.version().await?;
+
+            let flavor = match version.components.iter().flatten().any(|c| c.name.contains("Podman")) {
+                true => DockerFlavor::Podman,
+                false => DockerFlavor::Docker,
+            };
+
+            info!("using {:?} at {:?}", flavor, self.endpoint);
+
+            Ok::<_, DockerError>(flavor)
+        }).await;
+
+        match flavor {
+            Ok(s) => *s,
+            Err(e) => {
+                warn!("could not read the version of {:?}, assuming docker. error: {:#?}", self.endpoint, e);
+                DockerFlavor::Docker
+            }
+        }
+    }
+
+    async fn list_containers_filtered(&self, filters: HashMap<String, Vec<String>>) -> Result<Vec<ContainerSummary>, ::anyhow::Error> {
+        let containers = self.docker.list_containers(Some(ListContainersOptions::<String> {
+            all: true,
+            filters,
+            ..Default::default()
+        })).await.context("could not read containers from docker container")?;
+
+        let flavor = self.get_flavor().await;
+
+        Ok(containers.into_iter().map(|c| normalize_container_summary(c, flavor)).collect())
+    }
+}
+
+// podman answers in its own dialect at some places, the rest of easyharun only knows docker's.
+pub fn normalize_container_summary(mut container: ContainerSummary, flavor: DockerFlavor) -> ContainerSummary {
+    // docker prefixes names with a slash, podman does not.
+    container.names = container.names.map(|names| names.into_iter()
+        .map(|n| match n.starts_with('/') {
+            true => n,
+            false => format!("/{}", n),
+        })
+        .collect()
+    );
+
+    if flavor == DockerFlavor::Podman {
+        container.state = container.state.map(|state| match state.as_str() {
+            "configured" | "initialized" => "created".to_string(),
+            "stopped" | "stopping" => "exited".to_string(),
+            _ => state,
+        });
+
+        // podman drops labels with an empty value, easyharun_health_checks can be empty.
+        if let Some(labels) = container.labels.as_mut() {
+            if labels.contains_key("easyharun") {
+                labels.entry("easyharun_health_checks".to_string()).or_default();
+            }
+        }
+    }
+
+    // docker reports ipv4 and ipv6 bindings as separate ports, podman may report a port without a host binding.
+    if let Some(ports) = container.ports.as_mut() {
+        ports.sort_by_key(|p| (p.private_port, p.typ, p.public_port.is_none()));
+        ports.dedup_by_key(|p| (p.private_port, p.typ));
+    }
+
+    container
+}
+
+#[async_trait]
+impl ContainerRuntime for DockerRuntime {
+    async fn list_containers(&self) -> Result<Vec<ContainerSummary>, ::anyhow::Error> {
+        self.list_containers_filtered(HashMap::new()).await
+    }
+
+    async fn list_containers_in_namespace(&self, namespace: &str) -> Result<Vec<ContainerSummary>, ::anyhow::Error> {
+        // containers of the default namespace may lack the label, docker can not filter on a missing label.
+        let filters = match namespace == DEFAULT_NAMESPACE {
+            true => HashMap::new(),
+            false => HashMap::from([("label".to_string(), vec![format!("{}={}", LABEL_NAMESPACE, namespace)])]),
+        };
+
+        Ok(self.list_containers_filtered(filters).await?.into_iter().filter(|c| is_container_in_namespace(c, namespace)).collect())
+    }
+
+    async fn pull_image(&self, image: &str) -> Result<ImagePull, ::anyhow::Error> {
+        let present = match self.docker.inspect_image(image).await {
+            Ok(_) => true,
+            Err(DockerError::DockerResponseServerError { status_code: 404, .. }) => false,
+            Err(e) => return Err(e).context(format!("could not inspect image {}", image)),
+        };
+
+        let mut create_image = self.docker.create_image(Some(CreateImageOptions {
+            from_image: image.to_string(),
+            ..Default::default()
+        }), None, None);
+
+        let mut failed = None;
+
+        while let Some(v) = create_image.next().await {
+            match v {
+                Ok(_) => debug!("getting image ..."),
+                Err(e) => failed = Some(e),
+            };
+        }
+
+        // a failed pull is fine as long as the image exists locally.
+        match (present, failed) {
+            (true, None) => Ok(ImagePull::AlreadyPresent),
+            (true, Some(e)) => {
+                warn!("could not pull image {}, using the local one. error: {:#?}", image, e);
+                Ok(ImagePull::AlreadyPresent)
+            },
+            (false, None) => Ok(ImagePull::Pulled),
+            (false, Some(e)) => Err(e).context(format!("could not pull image {}", image)),
+        }
+    }
+
+    async fn create_container(&self, name: &str, config: Config<String>) -> Result<(), ::anyhow::Error> {
+        self.docker.create_container(
+            Some(CreateContainerOptions {
+                name: name.to_string(),
+                platform: None,
+            }),
+            config,
+        ).await?;
+
+        Ok(())
+    }
+
+    async fn start_container(&self, name: &str) -> Result<(), ::anyhow::Error> {
+        self.docker.start_container(name, None::<StartContainerOptions<String>>).await?;
+
+        Ok(())
+    }
+
+    async fn stop_container(&self, container_id: &ContainerId, stop_grace_period_s: u32) -> Result<(), ::anyhow::Error> {
+        match self.docker.stop_container(container_id.as_str(), Some(StopContainerOptions { t: stop_grace_period_s as i64 })).await {
+            Ok(_) => Ok(()),
+            // 304, the container is already stopped.
+            Err(DockerError::DockerResponseServerError { status_code: 304, .. }) => Ok(()),
+            Err(e) => Err(e).context(format!("could not stop container {:?}", container_id)),
+        }
+    }
+
+    async fn remove_container(&self, container_id: &ContainerId) -> Result<(), ::anyhow::Error> {
+        match self.docker.remove_container(container_id.as_str(), Some(RemoveContainerOptions { force: true, ..Default::default() })).await {
+            Ok(_) => Ok(()),
+            Err(DockerError::DockerResponseServerError { status_code: 404, .. }) => Ok(()),
+            Err(e) => Err(e).context(format!("could not remove container {:?}", container_id)),
+        }
+    }
+
+    async fn remove_image(&self, image: &str) -> Result<bool, ::anyhow::Error> {
+        match self.docker.remove_image(image, None, None).await {
+            Ok(_) => Ok(true),
+            Err(DockerError::DockerResponseServerError { status_code: 404, .. }) => Ok(true),
+            Err(DockerError::DockerResponseServerError { status_code: 409, .. }) => Ok(false),
+            Err(e) => Err(e).context(format!("could not remove image {}", image)),
+        }
+    }
+
+    async fn inspect_container(&self, container_id: &ContainerId) -> Result<ContainerInspectResponse, ::anyhow::Error> {
+        self.docker.inspect_container(container_id.as_str(), None::<InspectContainerOptions>).await
+            .context(format!("could not inspect container {:?}", container_id))
+    }
+
+    async fn container_logs(&self, container_id: &ContainerId, tail: usize) -> Result<Vec<String>, ::anyhow::Error> {
+        let mut logs = vec![];
+        let mut log_stream = self.docker.logs(container_id.as_str(), Some(LogsOptions::<String> {
+            stdout: true,
+            stderr: true,
+            tail: tail.to_string(),
+            ..Default::default()
+        }));
+
+        while let Some(log) = log_stream.next().await {
+            let log = log.context(format!("could not read logs of container {:?}", container_id))?;
+            logs.extend(log.to_string().lines().map(|l| l.to_string()));
+        }
+
+        // a single log entry can contain multiple lines.
+        let skip = logs.len().saturating_sub(tail);
+
+        Ok(logs.into_iter().skip(skip).collect())
+    }
+
+    fn events(&self) -> BoxStream<'static, Result<EventMessage, ::anyhow::Error>> {
+        self.docker.events(None::<EventsOptions<String>>)
+            .map(|e| e.context("could not read docker events"))
+            .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use bollard::models::{Port, PortTypeEnum};
+    use super::*;
+
+    fn port(private_port: i64, public_port: Option<i64>) -> Port {
+        Port { private_port, public_port, typ: Some(PortTypeEnum::TCP), ..Default::default() }
+    }
+
+    fn udp_port(private_port: i64, public_port: Option<i64>) -> Port {
+        Port { private_port, public_port, typ: Some(PortTypeEnum::UDP), ..Default::default() }
+    }
+
+    #[test]
+    fn podman_containers_look_like_docker_containers() {
+        let container = ContainerSummary {
+            names: Some(vec!["web".to_string()]),
+            state: Some("configured".to_string()),
+            labels: Some(HashMap::from([("easyharun".to_string(), "1.0.0".to_string())])),
+            ports: Some(vec![port(80, None), port(80, Some(40001)), port(443, Some(40002)), udp_port(443, Some(40003))]),
+            ..Default::default()
+        };
+
+        let container = normalize_container_summary(container, DockerFlavor::Podman);
+
+        assert_eq!(Some(vec!["/web".to_string()]), container.names);
+        assert_eq!(Some("created".to_string()), container.state);
+        assert_eq!(Some(&"".to_string()), container.labels.unwrap().get("easyharun_health_checks"));
+        assert_eq!(Some(vec![port(80, Some(40001)), port(443, Some(40002)), udp_port(443, Some(40003))]), container.ports);
+    }
+}
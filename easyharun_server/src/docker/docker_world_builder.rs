@@ -1,18 +1,31 @@
-use std::collections::HashMap;
 use anyhow::{anyhow, Context};
-use bollard::container::ListContainersOptions;
 use tracing::{debug, trace, warn};
 
 use bollard::models::ContainerSummary;
 
-use easyharun_lib::ContainerId;
+use easyharun_lib::config::ConfigContainerUpdate;
+use easyharun_lib::container_labels::{is_managed_by_easyharun, ContainerLabels};
+use easyharun_lib::ContainerIdentity;
 
 use crate::container_manager::world::{World, WorldContainer};
-use crate::docker::docker_connection::docker_create_connection;
+use crate::container_runtime::ContainerRuntime;
 use crate::kv_container::KV;
 
 pub struct DockerRunningContainerInfo {
-    pub container_id: ContainerId,
+    pub identity: ContainerIdentity,
+}
+
+// every part of easyharun reads the identity of a container here, so the KV sees the same ids everywhere.
+pub fn read_container_identity(container: &ContainerSummary) -> Result<ContainerIdentity, ::anyhow::Error> {
+    let id = container.id.as_ref().ok_or_else(|| anyhow!("container without id"))?;
+
+    let name = container.names.as_ref()
+        .and_then(|names| names.first())
+        .ok_or_else(|| anyhow!("container {} without a name", id))?;
+
+    let labels = ContainerLabels::from_labels(&container.labels.clone().unwrap_or_default())?;
+
+    Ok(ContainerIdentity::new(id, name, &labels))
 }
 
 pub async fn docker_container_info(container: &ContainerSummary, kv : &KV) -> Option<DockerRunningContainerInfo> {
@@ -22,7 +35,7 @@ pub async fn docker_container_info(container: &ContainerSummary, kv : &KV) -> Op
         Some(s) => s,
     };
 
-    if labels.get("easyharun") != Some(&"1.0.0".to_string()) {
+    if !is_managed_by_easyharun(labels) {
         return None;
     }
 
@@ -45,77 +58,90 @@ pub async fn docker_container_info(container: &ContainerSummary, kv : &KV) -> Op
         }
     };
 
-    let container_id = ContainerId::new(match &container.names {
-        None => {
-            warn!("container without a name");
+    let identity = match read_container_identity(container) {
+        Ok(s) => s,
+        Err(e) => {
+            warn!("could not read the identity of container {:?}. error: {:#?}", container.id, e);
             return None;
         }
-        Some(s) => {
-            match s.first() {
-                None => {
-                    warn!("container without a name #2");
-                    return None;
-                },
-                Some(s) => s.to_string()
-            }
-        }
-    });
+    };
 
-    if kv.is_container_marked_to_be_deleted(&container_id).await {
+    if kv.is_container_marked_to_be_deleted(&identity.id).await {
         return None;
     }
 
     return Some(DockerRunningContainerInfo{
-        container_id
+        identity
     });
 }
 
-pub async fn build_world_from_docker(kv : &KV) -> Result<World, ::anyhow::Error> {
+pub async fn build_world_from_docker(runtime: &dyn ContainerRuntime, kv : &KV) -> Result<World, ::anyhow::Error> {
     trace!("starting to check the docker world");
 
-    let docker = docker_create_connection()?;
-
-    let filters : HashMap<String, Vec<String>> = HashMap::new();
-
-    let containers = docker.list_containers(Some(ListContainersOptions {
-        all: true,
-        size: false,
-        limit: None,
-        filters
-    })).await.context("could not read containers from docker container")?;
+    let containers = runtime.list_containers().await?;
 
     let mut world_containers = vec![];
     for container in containers.iter() {
 
-        let container_id = match docker_container_info(&container, kv).await {
-            Some(s) => s.container_id,
-            None => {
-                continue
-            }
-        };
+        if docker_container_info(container, kv).await.is_none() {
+            continue;
+        }
 
         match build_world_container(container) {
+            Ok(Some(mut c)) => {
+                c.healthy = is_container_healthy(&c, kv).await;
+                c.ready = c.healthy && is_container_used_by_proxies(&c, kv).await;
+                world_containers.push(c);
+            }
             Err(e) => {
                 warn!("error while scanning container {:?}. error: {:#?}", container.id, e);
             }
             Ok(None) => {
                 debug!("container {:?} is ignored.", container.id)
             },
-            Ok(Some(c)) => {
-                world_containers.push(c);
-            }
         };
     }
 
     Ok(World::new(world_containers))
 }
 
+// a container without health checks is healthy as soon as it runs.
+async fn is_container_healthy(container: &WorldContainer, kv : &KV) -> bool {
+    if container.health_checks.is_empty() {
+        return true;
+    }
+
+    match &container.container_id {
+        Some(container_id) => kv.is_container_healthy(container_id).await,
+        None => false,
+    }
+}
+
+async fn is_container_used_by_proxies(container: &WorldContainer, kv : &KV) -> bool {
+    let port_mapping = container.container_port_mapping.clone().unwrap_or_default();
+
+    for proxy in container.proxies.iter() {
+        match port_mapping.iter().find(|p| p.internal == proxy.container_port) {
+            Some(p) if kv.is_server_addr_used_by_proxy(&p.get_server_addr()).await => {},
+            _ => return false,
+        };
+    }
+
+    true
+}
+
 #[derive(Debug, Clone)]
 pub struct PortInternalDynamic {
     pub internal: u32,
     pub dynamic: u32, // port the host can access...
 }
 
+impl PortInternalDynamic {
+    pub fn get_server_addr(&self) -> String {
+        format!("127.0.0.1:{}", self.dynamic)
+    }
+}
+
 pub fn extract_dynamic_port_form_container(container_summary : &ContainerSummary) -> Result<Vec<PortInternalDynamic>, ::anyhow::Error> {
 
     let ports = match container_summary.ports.clone() {
@@ -134,68 +160,171 @@ pub fn extract_dynamic_port_form_container(container_summary : &ContainerSummary
     }).collect::<Result<Vec<PortInternalDynamic>, ::anyhow::Error>>()
 }
 
-pub fn build_world_container(container_summary : &ContainerSummary) -> Result<Option<WorldContainer>, ::anyhow::Error> {
-    let labels = container_summary.labels.clone().unwrap_or(HashMap::new());
-
-    debug!("inspecting container {}", container_summary.id.as_ref().unwrap_or(&"NO_ID".to_string()));
-
-    let container_id = match container_summary.id.clone() {
-        Some(s) => ContainerId::new(s),
-        None => return Err(anyhow!("container without id"))
+// containers of schema version 1 may not have a revision or spec hash, both are computed from their labels.
+// blue/green adopts them, and they are only replaced if their labels differ from the config.
+pub fn world_container_from_labels(labels: ContainerLabels) -> WorldContainer {
+    let mut container = WorldContainer {
+        container_id: None,
+        name: labels.name,
+        image: labels.image,
+        replica_id: labels.replica_id,
+        container_ports: labels.container_ports,
+        container_port_mapping: None,
+        health_checks: labels.health_checks,
+        proxies: labels.proxies,
+        stop_grace_period_s: labels.stop_grace_period_s,
+        restart_after_failures: None,
+        unhealthy_grace_s: 0,
+        runtime: labels.runtime,
+        update: ConfigContainerUpdate::default(),
+        canary_weight: None,
+        spec_hash: labels.spec_hash,
+        revision: labels.revision,
+        healthy: false,
+        ready: false,
     };
 
-    let name = match labels.get("easyharun_name") {
-        Some(s) => s.to_string(),
-        None => return Err(anyhow!("container without name"))
-    };
+    if container.revision.is_none() {
+        container.revision = Some(container.compute_revision());
+    }
 
-    let image = match labels.get("easyharun_image") {
-        Some(s) => s.to_string(),
-        None => return Err(anyhow!("container without image"))
-    };
+    if container.spec_hash.is_none() {
+        container.spec_hash = Some(container.compute_spec_hash());
+    }
 
-    let replica_id = match labels.get("easyharun_replica_id") {
-        Some(s) => match s.parse::<u32>() {
-            Ok(k) => k,
-            Err(e) => return Err(anyhow!("invalid easyharun_replica_id (not a number)"))
-        },
-        None => return Err(anyhow!("container without replica_id"))
-    };
+    container
+}
 
-    let container_ports = match labels.get("easyharun_container_ports") {
-        Some(s) => {
-            s.split(",").map(|v|{
-                match v.trim().parse::<u32>() {
-                    Ok(k) => Ok(k),
-                    Err(e) => return Err(anyhow!("invalid easyharun_container_port (not a number)"))
-                }
-            }).collect::<Result<Vec<u32>, ::anyhow::Error>>()?
-        },
-        None => return Err(anyhow!("container without easyharun_container_port"))
-    };
+pub fn build_world_container(container_summary : &ContainerSummary) -> Result<Option<WorldContainer>, ::anyhow::Error> {
+    let labels = container_summary.labels.clone().unwrap_or_default();
 
-    let health_checks = match labels.get("easyharun_health_checks") {
-        Some(s) => s.split(",").map(|x|x.to_string()).collect::<Vec<_>>(),
-        None => return Err(anyhow!("container without health_checks"))
-    };
+    debug!("inspecting container {}", container_summary.id.as_ref().unwrap_or(&"NO_ID".to_string()));
 
-    let proxies = match labels.get("easyharun_proxies") {
-        Some(s) => serde_json::from_str(s).context("could not parse easyharun_proxies entry")?,
-        None => return Err(anyhow!("container without proxies"))
-    };
+    let identity = read_container_identity(container_summary)?;
+
+    let container_labels = ContainerLabels::from_labels(&labels)?;
 
     let container_port_mapping = extract_dynamic_port_form_container(container_summary).context("could not extract container_dynamic_port_host")?;
 
     Ok(Some(
         WorldContainer {
-            container_id: Some(container_id),
-            name,
-            image,
-            replica_id,
-            container_ports,
+            container_id: Some(identity.id),
             container_port_mapping: Some(container_port_mapping),
-            health_checks,
-            proxies
+            ..world_container_from_labels(container_labels)
         }
     ))
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use easyharun_lib::config::DEFAULT_STOP_GRACE_PERIOD_S;
+    use std::sync::Arc;
+    use bollard::container::Config;
+    use easyharun_lib::ContainerId;
+    use crate::brain::brain_action::{BrainAction, ContainerStart, ContainerStop};
+    use crate::container_runtime::fake_runtime::FakeContainerRuntime;
+    use crate::docker::docker_action_executer::DockerActionExecuter;
+    use super::*;
+
+    #[tokio::test]
+    async fn a_stopped_container_is_hidden_from_all_managers() {
+        let runtime = FakeContainerRuntime::new();
+        let kv = KV::new();
+        let executer = DockerActionExecuter::new(kv.clone(), Arc::new(runtime.clone()), 1);
+
+        let container = WorldContainer {
+            name: "web".to_string(),
+            image: "web:1".to_string(),
+            replica_id: 2,
+            container_ports: vec![80],
+            ..Default::default()
+        };
+
+        executer.execute(&[BrainAction::ContainersStart(vec![ContainerStart::new_from_world_container(&container)])]).await.expect("started");
+
+        let summaries = runtime.list_containers().await.expect("containers");
+        let identity = read_container_identity(&summaries[0]).expect("identity");
+        assert_eq!(("web", 2, Some(container.compute_revision())), (identity.group.as_str(), identity.replica_id, identity.revision.clone()));
+        assert!(!identity.name.starts_with('/'));
+
+        let world = build_world_from_docker(&runtime, &kv).await.expect("world");
+        let world_container = world.get_containers()[0].clone();
+        assert_eq!(Some(identity.id.clone()), world_container.container_id);
+
+        executer.execute(&[BrainAction::ContainersStop(vec![ContainerStop {
+            id: world_container.container_id.clone().expect("container id"),
+            world_container,
+        }])]).await.expect("stopped");
+
+        assert_eq!(vec![identity.id], kv.get_containers_marked_to_be_deleted().await);
+
+        // the container and health check managers build their world from docker, the proxy manager filters with docker_container_info.
+        assert!(build_world_from_docker(&runtime, &kv).await.expect("world").get_containers().is_empty());
+        assert!(docker_container_info(&summaries[0], &kv).await.is_none());
+    }
+
+    // a container started by an older easyharun, with comma lists and without spec hash and revision.
+    fn v1_labels(name: &str, with_proxies: bool) -> HashMap<String, String> {
+        let mut labels = HashMap::from([
+            ("easyharun".to_string(), "1.0.0".to_string()),
+            ("easyharun_name".to_string(), name.to_string()),
+            ("easyharun_image".to_string(), "web:1".to_string()),
+            ("easyharun_replica_id".to_string(), "0".to_string()),
+            ("easyharun_container_ports".to_string(), "80".to_string()),
+            ("easyharun_health_checks".to_string(), "".to_string()),
+        ]);
+
+        if with_proxies {
+            labels.insert("easyharun_proxies".to_string(), "[]".to_string());
+        }
+
+        labels
+    }
+
+    #[tokio::test]
+    async fn containers_of_older_label_schemas_are_adopted() {
+        let runtime = FakeContainerRuntime::new();
+        let kv = KV::new();
+        let executer = DockerActionExecuter::new(kv.clone(), Arc::new(runtime.clone()), 1);
+
+        runtime.pull_image("web:1").await.expect("pulled");
+        for (name, with_proxies) in [("web", true), ("broken", false)] {
+            runtime.create_container(name, Config {
+                image: Some("web:1".to_string()),
+                labels: Some(v1_labels(name, with_proxies)),
+                exposed_ports: Some(HashMap::from([("80/tcp".to_string(), HashMap::new())])),
+                ..Default::default()
+            }).await.expect("created");
+            runtime.start_container(name).await.expect("started");
+        }
+
+        executer.adopt_older_label_schemas().await.expect("adopted");
+
+        let world = build_world_from_docker(&runtime, &kv).await.expect("world");
+        assert_eq!(1, world.get_containers().len());
+
+        let adopted = &world.get_containers()[0];
+        assert_eq!(("web", vec![80]), (adopted.name.as_str(), adopted.container_ports.clone()));
+        assert_eq!(Some(adopted.compute_revision()), adopted.revision);
+
+        // the hash is computed from the labels, so the container only stays while the config matches them.
+        let mut expected = WorldContainer {
+            name: "web".to_string(),
+            image: "web:1".to_string(),
+            container_ports: vec![80],
+            stop_grace_period_s: DEFAULT_STOP_GRACE_PERIOD_S,
+            ..Default::default()
+        };
+        assert!(adopted.has_spec_hash(&expected.compute_spec_hash()));
+
+        expected.runtime.env.insert("FOO".to_string(), "bar".to_string());
+        assert!(!adopted.has_spec_hash(&expected.compute_spec_hash()));
+
+        let broken = runtime.list_containers().await.expect("containers").into_iter()
+            .find(|c| c.names == Some(vec!["/broken".to_string()]))
+            .and_then(|c| c.id)
+            .expect("broken container");
+        assert_eq!(vec![ContainerId::new(broken)], kv.get_containers_marked_to_be_deleted().await);
+    }
+}
@@ -1,3 +1,4 @@
 pub mod docker_world_builder;
 pub mod docker_action_executer;
-pub mod docker_connection;
\ No newline at end of file
+pub mod docker_connection;
+pub mod docker_runtime;
\ No newline at end of file
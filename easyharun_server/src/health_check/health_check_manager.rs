@@ -11,6 +11,7 @@ use easyharun_lib::config::{Config, ConfigFileHealthCheck};
 use easyharun_lib::ContainerId;
 use crate::config::config_provider::{ConfigReader};
 use crate::container_manager::world::WorldContainer;
+use crate::container_runtime::ContainerRuntimeRef;
 use crate::docker::docker_world_builder::build_world_from_docker;
 use crate::health_check::{HealthCheckMsgRecv, HealthCheckMsgRecvCheckFailed, HealthCheckMsgRecvCheckOk};
 use crate::health_check::http::health_check_http::{HealthCheckHttp};
@@ -18,6 +19,7 @@ use crate::kv_container::KV;
 
 pub struct HealthCheckHttpConfig {
     pub container_id: ContainerId,
+    pub check: String,
     pub url: String,
     pub timeout_ms: u32,
 }
@@ -44,15 +46,19 @@ pub struct HealthCheckManager {
     health_checks: HashMap<ContainerId, Vec<(String, HealthCheck)>>,
     actor_state: ActorState<HealthCheckMsgRecv>,
     config_reader: ConfigReader,
+    config_generation_applied: u64,
+    runtime: ContainerRuntimeRef,
     kv: KV,
 }
 
 impl HealthCheckManager {
-    pub fn new(actor_state: ActorState<HealthCheckMsgRecv>, config_reader: ConfigReader, kv: KV) -> Self {
+    pub fn new(actor_state: ActorState<HealthCheckMsgRecv>, config_reader: ConfigReader, runtime: ContainerRuntimeRef, kv: KV) -> Self {
         Self {
             health_checks: HashMap::new(),
             actor_state,
             config_reader,
+            config_generation_applied: 0,
+            runtime,
             kv
         }
     }
@@ -81,32 +87,35 @@ impl Actor for HealthCheckManager {
 
 impl HealthCheckManager {
 
-    pub async fn run_inner_got_msg(&self, msg : HealthCheckMsgRecv) -> Result<(), ::anyhow::Error> {
+    pub async fn run_inner_got_msg(&mut self, msg : HealthCheckMsgRecv) -> Result<(), ::anyhow::Error> {
 
         match msg {
             HealthCheckMsgRecv::CheckFailed(msg) => self.on_health_check_failed(msg).await?,
             HealthCheckMsgRecv::CheckOk(msg) => self.on_health_check_ok(msg).await?,
+            HealthCheckMsgRecv::ConfigChanged(_) => self.run_inner_maintain_checks().await?,
+            HealthCheckMsgRecv::ContainersChanged(_) => self.run_inner_maintain_checks().await?,
         };
 
         Ok(())
     }
 
     pub async fn on_health_check_failed(&self, msg : HealthCheckMsgRecvCheckFailed) -> Result<(), ::anyhow::Error> {
-        info!("health check failed {:?}", msg);
-        self.kv.mark_container_target_healthy(&msg.container_id, &msg.target, false).await;
+        info!("health check failed {}", self.config_reader.redact(&format!("{:?}", msg)));
+        self.kv.mark_health_check(&msg.container_id, &msg.check, false, Some(&msg.reason)).await;
         Ok(())
     }
 
     pub async fn on_health_check_ok(&self, msg : HealthCheckMsgRecvCheckOk) -> Result<(), ::anyhow::Error> {
-        info!("health check ok {:?}", msg);
-        self.kv.mark_container_target_healthy(&msg.container_id, &msg.target, true).await;
+        info!("health check ok {}", self.config_reader.redact(&format!("{:?}", msg)));
+        self.kv.mark_health_check(&msg.container_id, &msg.check, true, None).await;
         Ok(())
     }
 
     pub async fn run_inner_maintain_checks(&mut self) -> Result<(), ::anyhow::Error> {
-        let container_world = build_world_from_docker(&self.kv).await.context("check docker")?;
+        let container_world = build_world_from_docker(self.runtime.as_ref(), &self.kv).await.context("check docker")?;
 
-        let config = self.config_reader.get_copy().await;
+        let config_snapshot = self.config_reader.get_snapshot();
+        let config = config_snapshot.config.as_ref();
 
         let container_ids_that_have_checks_running = {
             let mut buf = HashSet::new();
@@ -144,9 +153,14 @@ impl HealthCheckManager {
                 };
 
                 info!("Starting Health Checks for {:?}", world_container.container_id);
+
+                // registered before the first result, so the backends are not ready until every check passed.
+                let backends = world_container.container_port_mapping.iter().flatten().map(|p| p.get_server_addr()).collect::<Vec<_>>();
+                self.kv.register_health_checks(container_id, &world_container.health_checks, &backends).await;
+
                 self.health_checks.insert(
                     container_id.clone(),
-                    self.build_health_checks_for_container(&world_container, &config).context("could not build health checks")?,
+                    self.build_health_checks_for_container(&world_container, config).context("could not build health checks")?,
                 );
             }
         }
@@ -172,9 +186,15 @@ impl HealthCheckManager {
                 };
 
                 self.health_checks.remove(container_id);
+                self.kv.forget_container_health(container_id).await;
             }
         }
 
+        if self.config_generation_applied != config_snapshot.generation {
+            self.config_generation_applied = config_snapshot.generation;
+            self.config_reader.set_applied_generation("HealthCheckManager", config_snapshot.generation).await;
+        }
+
         Ok(())
     }
 
@@ -251,6 +271,7 @@ impl HealthCheckManager {
 
         let check_config = HealthCheckHttpConfig {
             container_id: container_id.clone(),
+            check: health_check_name.to_string(),
             url: Self::template_parse_world_container(&config_file_health_check.url, world_container).context("template_parse_world_container")?,
             timeout_ms: config_file_health_check.timeout_ms,
         };
@@ -67,6 +67,7 @@ impl HealthCheckHttp {
             Err(e) => {
                 self.sender.send(HealthCheckMsgRecv::CheckFailed(HealthCheckMsgRecvCheckFailed {
                     container_id: self.check_config.container_id.clone(),
+                    check: self.check_config.check.clone(),
                     target: url.to_string(),
                     reason: "Timeout".to_string(),
                 })).await?;
@@ -78,6 +79,7 @@ impl HealthCheckHttp {
                 Err(e) => {
                     self.sender.send(HealthCheckMsgRecv::CheckFailed(HealthCheckMsgRecvCheckFailed {
                         container_id: self.check_config.container_id.clone(),
+                        check: self.check_config.check.clone(),
                         target: url.to_string(),
                         reason: format!("Error : {:?}", e),
                     })).await?;
@@ -90,6 +92,7 @@ impl HealthCheckHttp {
         if !response.status().is_success() {
             self.sender.send(HealthCheckMsgRecv::CheckFailed(HealthCheckMsgRecvCheckFailed {
                 container_id: self.check_config.container_id.clone(),
+                check: self.check_config.check.clone(),
                 target: url.to_string(),
                 reason: format!("Unsuccessful response : {:?}", response),
             })).await?;
@@ -99,6 +102,7 @@ impl HealthCheckHttp {
 
         self.sender.send(HealthCheckMsgRecv::CheckOk(HealthCheckMsgRecvCheckOk {
             container_id: self.check_config.container_id.clone(),
+            check: self.check_config.check.clone(),
             target: url.to_string(),
         })).await?;
 
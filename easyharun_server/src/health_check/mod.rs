@@ -1,4 +1,6 @@
 use easyharun_lib::ContainerId;
+use crate::config::config_provider::ConfigChanged;
+use crate::container_watcher::ContainersChanged;
 
 pub mod health_check_manager;
 pub mod http;
@@ -6,6 +8,7 @@ pub mod http;
 #[derive(Debug)]
 pub struct HealthCheckMsgRecvCheckFailed {
     container_id: ContainerId,
+    check: String,
     target: String,
     reason: String,
 }
@@ -13,6 +16,7 @@ pub struct HealthCheckMsgRecvCheckFailed {
 #[derive(Debug)]
 pub struct HealthCheckMsgRecvCheckOk {
     container_id: ContainerId,
+    check: String,
     target: String,
 }
 
@@ -20,4 +24,6 @@ pub struct HealthCheckMsgRecvCheckOk {
 pub enum HealthCheckMsgRecv {
     CheckFailed(HealthCheckMsgRecvCheckFailed),
     CheckOk(HealthCheckMsgRecvCheckOk),
+    ConfigChanged(ConfigChanged),
+    ContainersChanged(ContainersChanged),
 }
\ No newline at end of file
@@ -0,0 +1,241 @@
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::time::Instant;
+use serde::{Deserialize, Serialize};
+use easyharun_lib::ContainerId;
+use crate::kv_container::state::HealthFailures;
+use crate::kv_container::state_store::serde_instant;
+
+// a check guards every backend (proxy server addr) of its container.
+#[derive(Clone, Debug, Hash, Eq, PartialEq, Serialize, Deserialize)]
+pub struct HealthKey {
+    pub container_id: ContainerId,
+    pub check: String,
+    // None for containers without ports, they do not serve a proxy.
+    pub backend: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum HealthStatus {
+    // registered, but the check did not report yet.
+    Unknown,
+    Healthy,
+    Unhealthy,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CheckHealth {
+    pub status: HealthStatus,
+    #[serde(with = "serde_instant")]
+    pub last_transition: Instant,
+    pub consecutive_successes: u32,
+    pub consecutive_failures: u32,
+    pub last_reason: Option<String>,
+}
+
+impl CheckHealth {
+    fn new(now: Instant) -> Self {
+        Self {
+            status: HealthStatus::Unknown,
+            last_transition: now,
+            consecutive_successes: 0,
+            consecutive_failures: 0,
+            last_reason: None,
+        }
+    }
+
+    // true if the status changed.
+    fn record(&mut self, ok: bool, reason: Option<&str>, now: Instant) -> bool {
+        let status = match ok {
+            true => HealthStatus::Healthy,
+            false => HealthStatus::Unhealthy,
+        };
+
+        if ok {
+            self.consecutive_successes += 1;
+            self.consecutive_failures = 0;
+        } else {
+            self.consecutive_successes = 0;
+            self.consecutive_failures += 1;
+        }
+
+        self.last_reason = reason.map(|r| r.to_string());
+
+        if self.status == status {
+            return false;
+        }
+
+        self.status = status;
+        self.last_transition = now;
+        true
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct HealthRegistry {
+    checks: HashMap<HealthKey, CheckHealth>,
+}
+
+impl HealthRegistry {
+    // starts every check of the container as unknown, results of checks that are not registered are dropped.
+    // true if a check was not registered before.
+    pub fn register(&mut self, container_id: &ContainerId, checks: &[String], backends: &[String], now: Instant) -> bool {
+        let backends = match backends.is_empty() {
+            true => vec![None],
+            false => backends.iter().map(|b| Some(b.to_string())).collect(),
+        };
+
+        let mut registered = false;
+
+        for check in checks {
+            for backend in backends.iter() {
+                let key = HealthKey {
+                    container_id: container_id.clone(),
+                    check: check.to_string(),
+                    backend: backend.clone(),
+                };
+
+                if let Entry::Vacant(entry) = self.checks.entry(key) {
+                    entry.insert(CheckHealth::new(now));
+                    registered = true;
+                }
+            }
+        }
+
+        registered
+    }
+
+    // true if the status of the check changed.
+    pub fn record(&mut self, container_id: &ContainerId, check: &str, ok: bool, reason: Option<&str>, now: Instant) -> bool {
+        self.checks.iter_mut()
+            .filter(|(key, _)| &key.container_id == container_id && key.check == check)
+            .fold(false, |changed, (_, health)| health.record(ok, reason, now) || changed)
+    }
+
+    // true if the container had checks.
+    pub fn forget_container(&mut self, container_id: &ContainerId) -> bool {
+        let len = self.checks.len();
+        self.checks.retain(|key, _| &key.container_id != container_id);
+        self.checks.len() != len
+    }
+
+    // true if a container that is not in container_ids had checks.
+    pub fn retain_containers(&mut self, container_ids: &HashSet<ContainerId>) -> bool {
+        let len = self.checks.len();
+        self.checks.retain(|key, _| container_ids.contains(&key.container_id));
+        self.checks.len() != len
+    }
+
+    // all checks of the container passed their last run.
+    pub fn is_container_healthy(&self, container_id: &ContainerId) -> bool {
+        let mut checks = self.checks.iter().filter(|(key, _)| &key.container_id == container_id).peekable();
+
+        checks.peek().is_some() && checks.all(|(_, health)| health.status == HealthStatus::Healthy)
+    }
+
+    pub fn has_backend(&self, backend: &str) -> bool {
+        self.checks.keys().any(|key| key.backend.as_deref() == Some(backend))
+    }
+
+    // all checks of the backend passed their last run, a backend without registered checks is not ready.
+    pub fn is_backend_ready(&self, backend: &str) -> bool {
+        let mut checks = self.checks.iter().filter(|(key, _)| key.backend.as_deref() == Some(backend)).peekable();
+
+        checks.peek().is_some() && checks.all(|(_, health)| health.status == HealthStatus::Healthy)
+    }
+
+    pub fn get_health_failures(&self, container_id: &ContainerId) -> HealthFailures {
+        let failing = self.checks.iter()
+            .filter(|(key, health)| &key.container_id == container_id && health.status == HealthStatus::Unhealthy)
+            .map(|(_, health)| health)
+            .collect::<Vec<_>>();
+
+        HealthFailures {
+            consecutive: failing.iter().map(|health| health.consecutive_failures).max().unwrap_or_default(),
+            unhealthy_since: failing.iter().map(|health| health.last_transition).min(),
+        }
+    }
+
+    pub fn from_entries(entries: Vec<(HealthKey, CheckHealth)>) -> Self {
+        Self {
+            checks: entries.into_iter().collect(),
+        }
+    }
+
+    pub fn get_entries(&self) -> Vec<(HealthKey, CheckHealth)> {
+        self.checks.iter().map(|(key, health)| (key.clone(), health.clone())).collect()
+    }
+
+    pub fn get_container_health(&self, container_id: &ContainerId) -> Vec<(HealthKey, CheckHealth)> {
+        let mut checks = self.checks.iter()
+            .filter(|(key, _)| &key.container_id == container_id)
+            .map(|(key, health)| (key.clone(), health.clone()))
+            .collect::<Vec<_>>();
+
+        checks.sort_by(|(a, _), (b, _)| (&a.check, &a.backend).cmp(&(&b.check, &b.backend)));
+
+        checks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::*;
+
+    #[test]
+    fn a_failing_check_makes_a_healthy_backend_unready() {
+        let mut registry = HealthRegistry::default();
+        let now = Instant::now();
+        let container_id = ContainerId::new("c1".to_string());
+        let backends = vec!["127.0.0.1:40001".to_string()];
+
+        registry.register(&container_id, &["http".to_string(), "tcp".to_string()], &backends, now);
+        assert!(!registry.is_container_healthy(&container_id));
+        assert!(!registry.is_backend_ready("127.0.0.1:40001"));
+        assert!(!registry.is_backend_ready("127.0.0.1:40002"));
+        assert!(!registry.has_backend("127.0.0.1:40002"));
+
+        registry.record(&container_id, "http", true, None, now);
+        registry.record(&container_id, "tcp", true, None, now);
+        assert!(registry.is_container_healthy(&container_id));
+        assert!(registry.is_backend_ready("127.0.0.1:40001"));
+
+        let later = now + Duration::from_secs(5);
+        registry.record(&container_id, "http", false, Some("Timeout"), later);
+        registry.record(&container_id, "http", false, Some("Timeout"), later + Duration::from_secs(1));
+        assert!(!registry.is_container_healthy(&container_id));
+        assert!(!registry.is_backend_ready("127.0.0.1:40001"));
+
+        // the passing tcp check does not reset the failures of the http check.
+        registry.record(&container_id, "tcp", true, None, later + Duration::from_secs(1));
+        let failures = registry.get_health_failures(&container_id);
+        assert_eq!((2, Some(later)), (failures.consecutive, failures.unhealthy_since));
+
+        let (key, health) = registry.get_container_health(&container_id).remove(0);
+        assert_eq!("http", key.check);
+        assert_eq!(HealthStatus::Unhealthy, health.status);
+        assert_eq!(later, health.last_transition);
+        assert_eq!((0, 2), (health.consecutive_successes, health.consecutive_failures));
+        assert_eq!(Some("Timeout".to_string()), health.last_reason);
+
+        registry.forget_container(&container_id);
+        assert!(!registry.has_backend("127.0.0.1:40001"));
+    }
+
+    #[tokio::test]
+    async fn a_backend_with_checks_is_not_ready_before_they_are_registered() {
+        let kv = crate::kv_container::KV::new();
+        let container_id = ContainerId::new("c1".to_string());
+        let backend = "127.0.0.1:40001".to_string();
+
+        assert!(kv.is_backend_ready(&backend).await);
+
+        kv.set_health_checked_backends([backend.clone()].into_iter().collect()).await;
+        assert!(!kv.is_backend_ready(&backend).await);
+
+        kv.register_health_checks(&container_id, &["http".to_string()], ::std::slice::from_ref(&backend)).await;
+        kv.mark_health_check(&container_id, "http", true, None).await;
+        assert!(kv.is_backend_ready(&backend).await);
+    }
+}
@@ -1,7 +1,18 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
 use std::sync::Arc;
-use ::tokio::sync::RwLock;
+use std::time::Instant;
+use ::tokio::sync::{Mutex, RwLock};
+use anyhow::Context;
+use tracing::{info, warn};
 use easyharun_lib::ContainerId;
+use crate::kv_container::health_registry::{CheckHealth, HealthKey, HealthRegistry};
+use crate::kv_container::state::{CanaryDecision, CanaryStatus, ContainerExit, ContainerGroupHistory, ContainerGroupStatus, ContainerReplacement, CrashLoop, HealthFailures};
+use crate::kv_container::state_store::{PersistedState, StateStore, STATE_VERSION};
+
+pub mod health_registry;
+pub mod state;
+pub mod state_store;
 
 
 #[derive(Eq, PartialEq, Debug)]
@@ -9,13 +20,6 @@ pub struct ContainerState {
     should_be_deleted: bool,
 }
 
-#[derive(Eq, PartialEq, Debug)]
-pub struct HealthState {
-    container: Option<ContainerId>,
-    target: String,
-    healthy: bool,
-}
-
 
 impl ContainerState {
     pub fn new_default() -> Self {
@@ -25,45 +29,430 @@ impl ContainerState {
     }
 }
 
+#[derive(Clone, Debug, Default)]
+pub struct ProxyConnectionStats {
+    pub connections: u64,
+    pub errors: u64,
+}
+
 #[derive(Clone, Debug)]
 pub struct KV {
     container: Arc<RwLock<HashMap<String, ContainerState>>>,
-    health: Arc<RwLock<HashMap<String, HealthState>>>,
+    health: Arc<RwLock<HealthRegistry>>,
+    proxy_server_addrs: Arc<RwLock<HashMap<String, HashSet<String>>>>,
+    // server addrs of containers that declare health checks, set by the ProxyManager before it routes to them.
+    health_checked_backends: Arc<RwLock<HashSet<String>>>,
+    // by container name.
+    container_groups: Arc<RwLock<BTreeMap<String, ContainerGroupHistory>>>,
+    container_group_status: Arc<RwLock<Vec<ContainerGroupStatus>>>,
+    // revision the proxies route to, by container name. only set for blue/green containers.
+    live_revisions: Arc<RwLock<HashMap<String, String>>>,
+    // by canary revision.
+    canary_decisions: Arc<RwLock<HashMap<String, CanaryDecision>>>,
+    canary_status: Arc<RwLock<Vec<CanaryStatus>>>,
+    // by listen addr, then server addr. only set for proxies with canary servers.
+    proxy_canary_weights: Arc<RwLock<HashMap<String, HashMap<String, u32>>>>,
+    // by server addr.
+    proxy_connection_stats: Arc<RwLock<HashMap<String, ProxyConnectionStats>>>,
+    // latest last, see MAX_CONTAINER_REPLACEMENTS.
+    container_replacements: Arc<RwLock<Vec<ContainerReplacement>>>,
+    // by container identifier, so a new image or port list starts from scratch.
+    crash_loops: Arc<RwLock<HashMap<String, CrashLoop>>>,
+    // by docker id, when the exit was noticed.
+    exited_containers: Arc<RwLock<HashMap<String, Instant>>>,
+    // images easyharun pulled, the gc removes them once no container uses them.
+    pulled_images: Arc<RwLock<HashSet<String>>>,
+    // None keeps everything in memory only.
+    store: Option<Arc<Mutex<StateStore>>>,
 }
 
+const MAX_CONTAINER_REPLACEMENTS: usize = 100;
+
 impl KV {
 
     pub fn new() -> KV {
         Self {
             container: Arc::new(RwLock::new(HashMap::new())),
-            health: Arc::new(RwLock::new(HashMap::new()))
+            health: Arc::new(RwLock::new(HealthRegistry::default())),
+            proxy_server_addrs: Arc::new(RwLock::new(HashMap::new())),
+            health_checked_backends: Arc::new(RwLock::new(HashSet::new())),
+            container_groups: Arc::new(RwLock::new(BTreeMap::new())),
+            container_group_status: Arc::new(RwLock::new(vec![])),
+            live_revisions: Arc::new(RwLock::new(HashMap::new())),
+            canary_decisions: Arc::new(RwLock::new(HashMap::new())),
+            canary_status: Arc::new(RwLock::new(vec![])),
+            proxy_canary_weights: Arc::new(RwLock::new(HashMap::new())),
+            proxy_connection_stats: Arc::new(RwLock::new(HashMap::new())),
+            container_replacements: Arc::new(RwLock::new(vec![])),
+            crash_loops: Arc::new(RwLock::new(HashMap::new())),
+            exited_containers: Arc::new(RwLock::new(HashMap::new())),
+            pulled_images: Arc::new(RwLock::new(HashSet::new())),
+            store: None,
+        }
+    }
+
+    // restores the state of the last run and writes every change of it through to the state directory.
+    pub fn open(state_dir: &Path) -> Result<KV, ::anyhow::Error> {
+        let store = StateStore::new(state_dir);
+        let state = store.load().context("could not load state")?.unwrap_or_default();
+
+        info!(
+            "restored state from {:?}, {} containers to be deleted, {} crash loops",
+            state_dir,
+            state.containers_to_be_deleted.len(),
+            state.crash_loops.len()
+        );
+
+        let kv = Self {
+            container: Arc::new(RwLock::new(state.containers_to_be_deleted.into_iter()
+                .map(|id| (id, ContainerState { should_be_deleted: true }))
+                .collect())),
+            health: Arc::new(RwLock::new(HealthRegistry::from_entries(state.health_checks))),
+            container_groups: Arc::new(RwLock::new(state.container_groups)),
+            live_revisions: Arc::new(RwLock::new(state.live_revisions)),
+            canary_decisions: Arc::new(RwLock::new(state.canary_decisions)),
+            container_replacements: Arc::new(RwLock::new(state.container_replacements)),
+            crash_loops: Arc::new(RwLock::new(state.crash_loops)),
+            exited_containers: Arc::new(RwLock::new(state.exited_containers)),
+            pulled_images: Arc::new(RwLock::new(state.pulled_images)),
+            store: Some(Arc::new(Mutex::new(store))),
+            ..Self::new()
+        };
+
+        Ok(kv)
+    }
+
+    // called after every change of the persisted state, a failed write is logged and retried with the next change.
+    async fn persist(&self) {
+        let store = match &self.store {
+            Some(s) => s,
+            None => return,
+        };
+
+        // held while reading the state, so an older state never overwrites a newer one.
+        let store = store.lock().await;
+
+        let state = PersistedState {
+            version: STATE_VERSION,
+            containers_to_be_deleted: self.get_containers_marked_to_be_deleted().await.into_iter().map(|id| id.as_str().to_string()).collect(),
+            health_checks: self.health.read().await.get_entries(),
+            container_replacements: self.get_container_replacements().await,
+            crash_loops: self.get_crash_loops().await,
+            exited_containers: self.exited_containers.read().await.clone(),
+            pulled_images: self.get_pulled_images().await,
+            container_groups: self.container_groups.read().await.iter().map(|(name, history)| (name.to_string(), history.without_env_values())).collect(),
+            live_revisions: self.live_revisions.read().await.clone(),
+            canary_decisions: self.get_canary_decisions().await,
+        };
+
+        // the write syncs the file and the directory, that must not block the runtime.
+        let writer = store.clone();
+        let saved = ::tokio::task::spawn_blocking(move || writer.save(&state)).await
+            .map_err(::anyhow::Error::from)
+            .and_then(|saved| saved);
+
+        if let Err(e) = saved {
+            warn!("could not persist state. error: {:#?}", e);
+        }
+    }
+
+    pub async fn register_health_checks(&self, container_id: &ContainerId, checks: &[String], backends: &[String]) {
+        if self.health.write().await.register(container_id, checks, backends, Instant::now()) {
+            self.persist().await;
         }
     }
 
-    pub async fn mark_container_target_healthy(&self, container_id: &ContainerId, target: &str, healthy : bool) {
-        if self.is_target_healthy(target).await {
-            return;
+    pub async fn mark_health_check(&self, container_id: &ContainerId, check: &str, ok: bool, reason: Option<&str>) {
+        // the counters are only persisted with the next transition, a result every second would be too many writes.
+        if self.health.write().await.record(container_id, check, ok, reason, Instant::now()) {
+            self.persist().await;
         }
+    }
+
+    pub async fn forget_container_health(&self, container_id: &ContainerId) {
+        if self.health.write().await.forget_container(container_id) {
+            self.persist().await;
+        }
+    }
 
-        // just do a write lock, if we need to change it.
-        self.health.write().await.entry(container_id.as_str().to_string()).or_insert_with(|| HealthState {
-            container: Some(container_id.clone()),
-            target: target.to_string(),
-            healthy,
-        }).healthy = healthy;
+    pub async fn get_container_health(&self, container_id: &ContainerId) -> Vec<(HealthKey, CheckHealth)> {
+        self.health.read().await.get_container_health(container_id)
     }
 
     pub async fn mark_container_to_be_deleted(&self, container_id: &ContainerId) {
         self.container.write().await.entry(container_id.as_str().to_string()).or_insert(ContainerState::new_default()).should_be_deleted = true;
+        self.persist().await;
     }
 
-    pub async fn is_target_healthy(&self, container_target: &str) -> bool {
-        let read = self.health.read().await;
+    pub async fn forget_container_to_be_deleted(&self, container_id: &ContainerId) {
+        if self.container.write().await.remove(container_id.as_str()).is_some() {
+            self.persist().await;
+        }
+    }
 
-        match read.get(container_target) {
-            Some(s) =>  s.healthy, // << has already the right state.
-            _ => false,
+    // drops the state of containers that vanished while easyharun was not running.
+    pub async fn retain_containers(&self, container_ids: &HashSet<ContainerId>) {
+        let removed_marks = {
+            let mut write = self.container.write().await;
+            let len = write.len();
+
+            write.retain(|id, _| container_ids.contains(&ContainerId::new(id.to_string())));
+            len - write.len()
+        };
+
+        let removed_health = self.health.write().await.retain_containers(container_ids);
+
+        let removed_exits = {
+            let mut write = self.exited_containers.write().await;
+            let len = write.len();
+
+            write.retain(|id, _| container_ids.contains(&ContainerId::new(id.to_string())));
+            len - write.len()
+        };
+
+        if removed_marks > 0 || removed_health || removed_exits > 0 {
+            info!("pruned the state of vanished containers, {} marked to be deleted, {} exited", removed_marks, removed_exits);
+            self.persist().await;
+        }
+    }
+
+    pub async fn get_containers_marked_to_be_deleted(&self) -> Vec<ContainerId> {
+        self.container.read().await.iter()
+            .filter(|(_, state)| state.should_be_deleted)
+            .map(|(id, _)| ContainerId::new(id.to_string()))
+            .collect()
+    }
+
+    pub async fn set_proxy_server_addrs(&self, listen_addr: &str, server_addrs: HashSet<String>) {
+        self.proxy_server_addrs.write().await.insert(listen_addr.to_string(), server_addrs);
+    }
+
+    pub async fn is_server_addr_used_by_proxy(&self, server_addr: &str) -> bool {
+        self.proxy_server_addrs.read().await.values().any(|server_addrs| server_addrs.contains(server_addr))
+    }
+
+    // set on every tick of the ContainerManager, the history only changes together with the status.
+    pub async fn set_container_groups(&self, groups: BTreeMap<String, ContainerGroupHistory>, status: Vec<ContainerGroupStatus>) {
+        *self.container_groups.write().await = groups;
+
+        {
+            let mut write = self.container_group_status.write().await;
+
+            if *write == status {
+                return;
+            }
+
+            *write = status;
+        }
+
+        self.persist().await;
+    }
+
+    pub async fn get_container_groups(&self) -> BTreeMap<String, ContainerGroupHistory> {
+        self.container_groups.read().await.clone()
+    }
+
+    pub async fn get_container_group_status(&self) -> Vec<ContainerGroupStatus> {
+        self.container_group_status.read().await.clone()
+    }
+
+    pub async fn set_live_revisions(&self, live_revisions: HashMap<String, String>) {
+        // set on every tick of the ContainerManager.
+        {
+            let mut write = self.live_revisions.write().await;
+
+            if *write == live_revisions {
+                return;
+            }
+
+            *write = live_revisions;
+        }
+
+        self.persist().await;
+    }
+
+    pub async fn get_live_revision(&self, container_name: &str) -> Option<String> {
+        self.live_revisions.read().await.get(container_name).cloned()
+    }
+
+    pub async fn set_canary_decision(&self, canary_revision: &str, decision: CanaryDecision) {
+        self.canary_decisions.write().await.insert(canary_revision.to_string(), decision);
+        self.persist().await;
+    }
+
+    pub async fn retain_canary_decisions(&self, canary_revisions: &HashSet<String>) {
+        let removed = {
+            let mut write = self.canary_decisions.write().await;
+            let len = write.len();
+
+            write.retain(|revision, _| canary_revisions.contains(revision));
+            write.len() != len
+        };
+
+        if removed {
+            self.persist().await;
+        }
+    }
+
+    pub async fn get_canary_decisions(&self) -> HashMap<String, CanaryDecision> {
+        self.canary_decisions.read().await.clone()
+    }
+
+    pub async fn set_canary_status(&self, status: Vec<CanaryStatus>) {
+        *self.canary_status.write().await = status;
+    }
+
+    pub async fn get_canary_status(&self) -> Vec<CanaryStatus> {
+        self.canary_status.read().await.clone()
+    }
+
+    pub async fn set_proxy_canary_weights(&self, listen_addr: &str, canary_weights: HashMap<String, u32>) {
+        let mut write = self.proxy_canary_weights.write().await;
+
+        match canary_weights.is_empty() {
+            true => write.remove(listen_addr),
+            false => write.insert(listen_addr.to_string(), canary_weights),
+        };
+    }
+
+    pub async fn get_proxy_canary_weights(&self, listen_addr: &str) -> Option<HashMap<String, u32>> {
+        self.proxy_canary_weights.read().await.get(listen_addr).cloned()
+    }
+
+    pub async fn record_proxy_connection(&self, server_addr: &str, ok: bool) {
+        let mut write = self.proxy_connection_stats.write().await;
+        let stats = write.entry(server_addr.to_string()).or_default();
+
+        stats.connections += 1;
+        if !ok {
+            stats.errors += 1;
+        }
+    }
+
+    pub async fn get_proxy_connection_stats(&self, server_addr: &str) -> ProxyConnectionStats {
+        self.proxy_connection_stats.read().await.get(server_addr).cloned().unwrap_or_default()
+    }
+
+    // the host port of a stopped container is reused by the next one.
+    pub async fn forget_proxy_connection_stats(&self, server_addr: &str) {
+        self.proxy_connection_stats.write().await.remove(server_addr);
+    }
+
+    // a check that keeps failing is not masked by the other checks of the container passing.
+    pub async fn get_health_failures(&self, container_id: &ContainerId) -> HealthFailures {
+        self.health.read().await.get_health_failures(container_id)
+    }
+
+    pub async fn record_container_replacement(&self, replacement: ContainerReplacement) {
+        {
+            let mut write = self.container_replacements.write().await;
+
+            write.push(replacement);
+            if write.len() > MAX_CONTAINER_REPLACEMENTS {
+                write.remove(0);
+            }
         }
+
+        self.persist().await;
+    }
+
+    pub async fn get_container_replacements(&self) -> Vec<ContainerReplacement> {
+        self.container_replacements.read().await.clone()
+    }
+
+    pub async fn record_container_exit(&self, identifier: &str, name: &str, replica_id: u32, exit: ContainerExit) -> CrashLoop {
+        let crash_loop = {
+            let mut write = self.crash_loops.write().await;
+
+            let crash_loop = CrashLoop::record_exit(write.get(identifier), name, replica_id, exit, Instant::now());
+            write.insert(identifier.to_string(), crash_loop.clone());
+
+            crash_loop
+        };
+
+        self.persist().await;
+
+        crash_loop
+    }
+
+    // replicas that are not in the config anymore.
+    pub async fn retain_crash_loops(&self, identifiers: &HashSet<String>) {
+        let removed = {
+            let mut write = self.crash_loops.write().await;
+            let len = write.len();
+
+            write.retain(|identifier, _| identifiers.contains(identifier));
+            write.len() != len
+        };
+
+        if removed {
+            self.persist().await;
+        }
+    }
+
+    pub async fn get_crash_loops(&self) -> HashMap<String, CrashLoop> {
+        self.crash_loops.read().await.clone()
+    }
+
+    // true if the exit was not noticed before.
+    pub async fn mark_container_exited(&self, container_id: &ContainerId) -> bool {
+        {
+            let mut write = self.exited_containers.write().await;
+
+            if write.contains_key(container_id.as_str()) {
+                return false;
+            }
+
+            write.insert(container_id.as_str().to_string(), Instant::now());
+        }
+
+        self.persist().await;
+        true
+    }
+
+    pub async fn get_container_exited_at(&self, container_id: &ContainerId) -> Option<Instant> {
+        self.exited_containers.read().await.get(container_id.as_str()).cloned()
+    }
+
+    pub async fn forget_container_exited(&self, container_id: &ContainerId) {
+        if self.exited_containers.write().await.remove(container_id.as_str()).is_some() {
+            self.persist().await;
+        }
+    }
+
+    pub async fn add_pulled_image(&self, image: &str) {
+        if self.pulled_images.write().await.insert(image.to_string()) {
+            self.persist().await;
+        }
+    }
+
+    pub async fn forget_pulled_image(&self, image: &str) {
+        if self.pulled_images.write().await.remove(image) {
+            self.persist().await;
+        }
+    }
+
+    pub async fn get_pulled_images(&self) -> HashSet<String> {
+        self.pulled_images.read().await.clone()
+    }
+
+    pub async fn set_health_checked_backends(&self, server_addrs: HashSet<String>) {
+        *self.health_checked_backends.write().await = server_addrs;
+    }
+
+    // the proxies only pick backends whose container passes all its checks.
+    pub async fn is_backend_ready(&self, server_addr: &str) -> bool {
+        let health = self.health.read().await;
+
+        match health.has_backend(server_addr) {
+            true => health.is_backend_ready(server_addr),
+            // the HealthCheckManager did not register the checks yet, only a container without checks is ready.
+            false => !self.health_checked_backends.read().await.contains(server_addr),
+        }
+    }
+
+    pub async fn is_container_healthy(&self, container_id: &ContainerId) -> bool {
+        self.health.read().await.is_container_healthy(container_id)
     }
 
     pub async fn is_container_marked_to_be_deleted(&self, container_id: &ContainerId) -> bool {
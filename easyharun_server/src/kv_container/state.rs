@@ -0,0 +1,156 @@
+use std::time::{Duration, Instant, SystemTime};
+use serde::{Deserialize, Serialize};
+use crate::container_manager::world::WorldContainer;
+use crate::kv_container::state_store::serde_instant;
+
+// the plain state the managers keep in the KV.
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum ContainerGroupState {
+    RollingOut,
+    Healthy,
+    RolledBack,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContainerGroupRevision {
+    pub revision: String,
+    // the expected containers, a rollback starts them again.
+    pub containers: Vec<WorldContainer>,
+    #[serde(with = "serde_instant")]
+    pub since: Instant,
+}
+
+impl ContainerGroupRevision {
+    fn without_env_values(&self) -> Self {
+        Self {
+            containers: self.containers.iter().map(|c| WorldContainer {
+                runtime: c.runtime.without_env_values(),
+                ..c.clone()
+            }).collect(),
+            ..self.clone()
+        }
+    }
+}
+
+// kept by the rollbacks of the ContainerManager, the last healthy revision must survive a restart.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContainerGroupHistory {
+    pub state: ContainerGroupState,
+    pub current: ContainerGroupRevision,
+    pub healthy: Option<ContainerGroupRevision>,
+    pub failed_revisions: Vec<String>,
+}
+
+impl ContainerGroupHistory {
+    // env values can be secrets, they are not written to the state. a rollback takes them from the config again.
+    pub fn without_env_values(&self) -> Self {
+        Self {
+            current: self.current.without_env_values(),
+            healthy: self.healthy.as_ref().map(|h| h.without_env_values()),
+            ..self.clone()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ContainerGroupStatus {
+    pub name: String,
+    pub state: ContainerGroupState,
+    // revision the config asks for.
+    pub revision: String,
+    // last revision where all replicas were ready.
+    pub healthy_revision: Option<String>,
+    pub failed_revisions: Vec<String>,
+}
+
+// made over the admin api, it belongs to the canary revision it was made for.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub enum CanaryDecision {
+    Promote,
+    Abort,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CanaryStatus {
+    pub name: String,
+    pub revision: String,
+    pub weight: u32,
+    pub replicas: u32,
+    pub healthy_replicas: u32,
+    pub connections: u64,
+    pub connection_errors: u64,
+}
+
+// failed runs in a row of the worst check of a container, derived from the HealthRegistry.
+#[derive(Debug, Clone, Default)]
+pub struct HealthFailures {
+    pub consecutive: u32,
+    pub unhealthy_since: Option<Instant>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContainerReplacement {
+    pub name: String,
+    pub replica_id: u32,
+    pub container_id: String,
+    pub reason: String,
+    pub replaced_at: SystemTime,
+}
+
+const CRASH_LOOP_BACKOFF_BASE_S: u64 = 2;
+const CRASH_LOOP_BACKOFF_MAX_S: u64 = 300;
+// a replica counts as crash looping once it exited this many times in a row.
+const CRASH_LOOP_AFTER_RESTARTS: u32 = 3;
+// a replica that ran this long without exiting starts from scratch.
+const CRASH_LOOP_RESET_AFTER_S: u64 = 600;
+
+// lines of the container log kept for every exit.
+pub const CRASH_LOOP_LOG_LINES: usize = 20;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContainerExit {
+    pub exit_code: Option<i64>,
+    pub logs: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CrashLoop {
+    pub name: String,
+    pub replica_id: u32,
+    pub restarts: u32,
+    pub last_exit: ContainerExit,
+    #[serde(with = "serde_instant")]
+    pub exited_at: Instant,
+    // the replica is not started again before.
+    #[serde(with = "serde_instant")]
+    pub backoff_until: Instant,
+}
+
+impl CrashLoop {
+    pub fn is_crash_looping(&self) -> bool {
+        self.restarts >= CRASH_LOOP_AFTER_RESTARTS
+    }
+
+    pub fn record_exit(previous: Option<&CrashLoop>, name: &str, replica_id: u32, exit: ContainerExit, now: Instant) -> Self {
+        let restarts = match previous {
+            Some(s) if now.duration_since(s.exited_at) < Duration::from_secs(CRASH_LOOP_RESET_AFTER_S) => s.restarts + 1,
+            _ => 1,
+        };
+
+        Self {
+            name: name.to_string(),
+            replica_id,
+            restarts,
+            last_exit: exit,
+            exited_at: now,
+            backoff_until: now + crash_loop_backoff(restarts),
+        }
+    }
+}
+
+// 2s, 4s, 8s, ... up to 5 minutes.
+pub fn crash_loop_backoff(restarts: u32) -> Duration {
+    let factor = 2u64.saturating_pow(restarts.saturating_sub(1));
+    Duration::from_secs(CRASH_LOOP_BACKOFF_BASE_S.saturating_mul(factor).min(CRASH_LOOP_BACKOFF_MAX_S))
+}
@@ -0,0 +1,239 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use anyhow::{anyhow, Context};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+use crate::kv_container::health_registry::{CheckHealth, HealthKey};
+use crate::kv_container::state::{CanaryDecision, ContainerGroupHistory, ContainerReplacement, CrashLoop};
+
+// bump it on every change of PersistedState that older versions can not read.
+pub const STATE_VERSION: u32 = 1;
+
+const STATE_FILE: &str = "state.json";
+
+// everything the KV keeps that can not be rebuilt from the container runtime.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct PersistedState {
+    pub version: u32,
+    pub containers_to_be_deleted: Vec<String>,
+    pub health_checks: Vec<(HealthKey, CheckHealth)>,
+    pub container_replacements: Vec<ContainerReplacement>,
+    pub crash_loops: HashMap<String, CrashLoop>,
+    #[serde(with = "serde_instant_map")]
+    pub exited_containers: HashMap<String, Instant>,
+    pub pulled_images: HashSet<String>,
+    pub container_groups: BTreeMap<String, ContainerGroupHistory>,
+    pub live_revisions: HashMap<String, String>,
+    pub canary_decisions: HashMap<String, CanaryDecision>,
+}
+
+#[derive(Deserialize)]
+struct PersistedStateVersion {
+    version: u32,
+}
+
+// a json file in the state directory, replaced atomically on every save.
+#[derive(Debug, Clone)]
+pub struct StateStore {
+    dir: PathBuf,
+}
+
+impl StateStore {
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    fn get_path(&self) -> PathBuf {
+        self.dir.join(STATE_FILE)
+    }
+
+    // None if there is no state yet. a file that can not be parsed is moved aside, so easyharun starts empty.
+    pub fn load(&self) -> Result<Option<PersistedState>, ::anyhow::Error> {
+        let path = self.get_path();
+
+        let content = match ::std::fs::read_to_string(&path) {
+            Ok(s) => s,
+            Err(e) if e.kind() == ::std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).context(format!("could not read state {:?}", path)),
+        };
+
+        let version = serde_json::from_str::<PersistedStateVersion>(&content).map(|v| v.version);
+
+        // moving it aside would lose the state of the newer version.
+        if let Ok(version) = version {
+            if version > STATE_VERSION {
+                return Err(anyhow!("state {:?} has version {}, this easyharun only reads up to {}", path, version, STATE_VERSION));
+            }
+        }
+
+        let state = version
+            .map_err(::anyhow::Error::from)
+            .and_then(|version| match version {
+                STATE_VERSION => serde_json::from_str::<PersistedState>(&content).map_err(::anyhow::Error::from),
+                version => Err(anyhow!("unknown state version {}", version)),
+            });
+
+        match state {
+            Ok(s) => Ok(Some(s)),
+            Err(e) => {
+                let corrupt = self.dir.join(format!("{}.corrupt", STATE_FILE));
+                warn!("could not parse state {:?}, moving it to {:?}. error: {:#?}", path, corrupt, e);
+                ::std::fs::rename(&path, &corrupt).context(format!("could not move state {:?} aside", path))?;
+                Ok(None)
+            }
+        }
+    }
+
+    // the state is written to a temporary file first, a crash never leaves a half written state behind.
+    pub fn save(&self, state: &PersistedState) -> Result<(), ::anyhow::Error> {
+        let path = self.get_path();
+        let tmp_path = self.dir.join(format!("{}.tmp", STATE_FILE));
+
+        let content = serde_json::to_vec(state).context("could not serialize state")?;
+
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+
+        // crash loops keep the last lines of the container logs.
+        #[cfg(unix)]
+        ::std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+        let mut file = options.open(&tmp_path).context(format!("could not create {:?}", tmp_path))?;
+        file.write_all(&content).context(format!("could not write {:?}", tmp_path))?;
+        file.sync_all().context(format!("could not sync {:?}", tmp_path))?;
+
+        ::std::fs::rename(&tmp_path, &path).context(format!("could not replace {:?}", path))?;
+
+        // the rename itself is only durable once the directory is synced.
+        File::open(&self.dir).and_then(|d| d.sync_all()).context(format!("could not sync {:?}", self.dir))?;
+
+        Ok(())
+    }
+}
+
+// instants only mean something within the process, on disk they are unix timestamps in ms.
+fn instant_to_unix_ms(instant: Instant) -> u64 {
+    let now_instant = Instant::now();
+    let now = SystemTime::now();
+
+    let at = match instant <= now_instant {
+        true => now.checked_sub(now_instant - instant),
+        false => now.checked_add(instant - now_instant),
+    };
+
+    at.unwrap_or(now).duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+fn unix_ms_to_instant(unix_ms: u64) -> Instant {
+    let now_instant = Instant::now();
+    let at = UNIX_EPOCH + Duration::from_millis(unix_ms);
+
+    match SystemTime::now().duration_since(at) {
+        Ok(ago) => now_instant.checked_sub(ago).unwrap_or(now_instant),
+        Err(e) => now_instant + e.duration(),
+    }
+}
+
+pub mod serde_instant {
+    use std::time::Instant;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(instant: &Instant, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(super::instant_to_unix_ms(*instant))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Instant, D::Error> {
+        Ok(super::unix_ms_to_instant(u64::deserialize(deserializer)?))
+    }
+}
+
+mod serde_instant_map {
+    use std::collections::HashMap;
+    use std::time::Instant;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(map: &HashMap<String, Instant>, serializer: S) -> Result<S::Ok, S::Error> {
+        map.iter()
+            .map(|(k, v)| (k, super::instant_to_unix_ms(*v)))
+            .collect::<HashMap<_, _>>()
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<HashMap<String, Instant>, D::Error> {
+        Ok(HashMap::<String, u64>::deserialize(deserializer)?.into_iter()
+            .map(|(k, v)| (k, super::unix_ms_to_instant(v)))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use easyharun_lib::ContainerId;
+    use crate::kv_container::state::ContainerExit;
+    use crate::kv_container::KV;
+    use super::*;
+
+    fn state_dir() -> PathBuf {
+        let dir = ::std::env::temp_dir().join(format!("easyharun-state-{}", uuid::Uuid::new_v4()));
+        ::std::fs::create_dir_all(&dir).expect("state dir");
+        dir
+    }
+
+    #[tokio::test]
+    async fn state_survives_a_restart() {
+        let dir = state_dir();
+        let container_id = ContainerId::new("abc".to_string());
+
+        {
+            let kv = KV::open(&dir).expect("opened");
+            kv.mark_container_to_be_deleted(&container_id).await;
+            kv.record_container_exit("web|web:1|0|80", "web", 0, ContainerExit { exit_code: Some(1), logs: vec!["boom".to_string()] }).await;
+            kv.set_canary_decision("rev1", CanaryDecision::Promote).await;
+            kv.register_health_checks(&container_id, &["http".to_string()], &["127.0.0.1:40001".to_string()]).await;
+            kv.mark_health_check(&container_id, "http", true, None).await;
+        }
+
+        let kv = KV::open(&dir).expect("reopened");
+        assert_eq!(vec![container_id.clone()], kv.get_containers_marked_to_be_deleted().await);
+        assert_eq!(1, kv.get_crash_loops().await["web|web:1|0|80"].restarts);
+        assert_eq!(Some(&CanaryDecision::Promote), kv.get_canary_decisions().await.get("rev1"));
+        assert!(kv.is_container_healthy(&container_id).await);
+        assert!(!dir.join(format!("{}.tmp", STATE_FILE)).exists());
+
+        // a state of a newer easyharun is never thrown away.
+        ::std::fs::write(dir.join(STATE_FILE), r#"{"version": 99}"#).expect("written");
+        assert!(KV::open(&dir).is_err());
+
+        ::std::fs::write(dir.join(STATE_FILE), "{not json").expect("written");
+        assert!(KV::open(&dir).expect("opened").get_containers_marked_to_be_deleted().await.is_empty());
+        assert!(dir.join(format!("{}.corrupt", STATE_FILE)).exists());
+
+        ::std::fs::remove_dir_all(&dir).expect("removed");
+    }
+
+    #[tokio::test]
+    async fn state_of_vanished_containers_is_pruned() {
+        let kv = KV::new();
+        let running = ContainerId::new("running".to_string());
+        let vanished = ContainerId::new("vanished".to_string());
+
+        for container_id in [&running, &vanished] {
+            kv.mark_container_to_be_deleted(container_id).await;
+            kv.register_health_checks(container_id, &["http".to_string()], &["127.0.0.1:40001".to_string()]).await;
+            kv.mark_container_exited(container_id).await;
+        }
+
+        kv.retain_containers(&HashSet::from([running.clone()])).await;
+
+        assert_eq!(vec![running.clone()], kv.get_containers_marked_to_be_deleted().await);
+        assert_eq!(1, kv.get_container_health(&running).await.len());
+        assert!(kv.get_container_health(&vanished).await.is_empty());
+        assert!(kv.get_container_exited_at(&running).await.is_some());
+        assert!(kv.get_container_exited_at(&vanished).await.is_none());
+    }
+}
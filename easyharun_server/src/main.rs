@@ -1,7 +1,10 @@
 #![allow(dead_code)]
 #![allow(unused_variables)]
 
+mod admin;
 mod container_manager;
+mod container_runtime;
+mod container_watcher;
 mod config;
 mod docker;
 mod brain;
@@ -12,6 +15,8 @@ mod tracing;
 mod _test_integration;
 
 use std::io::{Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use futures::future::OptionFuture;
 use structopt::StructOpt;
 use tokio::task::JoinHandle;
@@ -21,41 +26,120 @@ use tracing_subscriber::fmt::MakeWriter;
 use tracing_subscriber::util::SubscriberInitExt;
 use easyharun_lib::config::Config;
 
+use crate::admin::admin_run_grpc_server;
 use crate::config::ConfigMonitor;
-use crate::container_manager::ContainerManager;
+use crate::container_manager::{ContainerManager, ContainerManagerMsg};
+use crate::container_watcher::watcher::{ContainerWatcher, ContainerWatcherMsg};
+use crate::container_watcher::watched_runtime::WatchedContainerRuntime;
+use crate::container_manager::gc::GcOptions;
+use crate::container_runtime::ContainerRuntimeRef;
+use crate::container_runtime::namespace_lock::NamespaceLock;
+use crate::container_runtime::namespaced_runtime::NamespacedContainerRuntime;
+use crate::docker::docker_connection::RuntimeEndpoint;
+use crate::docker::docker_runtime::DockerRuntime;
 use crate::health_check::health_check_manager::HealthCheckManager;
 use crate::proxy::proxy_manager::ProxyManager;
+use crate::proxy::ProxyManagerMsg;
 use easyact::{Actor, actor_run_grpc_server, ActorConfig, ActorRegistry, ActorStateHandle};
 use crate::config::config_provider::{ConfigProvider, ConfigReader};
 use crate::health_check::HealthCheckMsgRecv;
 use crate::kv_container::KV;
-use crate::tracing::DebugWrite;
+use crate::tracing::{DebugWrite, tracing_init};
 
 #[derive(Debug, StructOpt)]
-#[structopt(name = "example", about = "An example of StructOpt usage.")]
+#[structopt(name = "easyharun_server", about = "Runs and proxies containers described by an easyharun config.")]
 struct Opt {
+    /// Config file or directory, a directory loads every *.toml file inside, files can include more files
+    #[structopt(short, long, env = "EASYHARUN_CONFIG", default_value = "./example/basic/easyharun.toml")]
+    config: String,
 
+    /// Listen address of the admin grpc server
+    #[structopt(long, env = "EASYHARUN_ADMIN_LISTEN", default_value = "0.0.0.0:50051")]
+    admin_listen: String,
+
+    /// Log filter, e.g. "info" or "easyharun_server=debug,warn"
+    #[structopt(long, env = "EASYHARUN_LOG_LEVEL", default_value = "info")]
+    log_level: String,
+
+    /// Log format
+    #[structopt(long, env = "EASYHARUN_LOG_FORMAT", default_value = "text", possible_values = &["text", "json"])]
+    log_format: String,
+
+    /// Directory where easyharun keeps its state, every namespace in its own subdirectory
+    #[structopt(long, env = "EASYHARUN_STATE_DIR", default_value = "./.easyharun")]
+    state_dir: String,
+
+    /// Directory of the namespace lock files, shared by all easyharun instances of a host [default: the temp directory].
+    /// The lock only covers one host, instances on two hosts sharing a tcp:// endpoint must use different namespaces
+    #[structopt(long, env = "EASYHARUN_LOCK_DIR")]
+    lock_dir: Option<String>,
+
+    /// Container engine: docker, podman, unix:///path/to.sock or tcp://host:port
+    #[structopt(long, env = "EASYHARUN_RUNTIME_ENDPOINT", default_value = "docker")]
+    runtime_endpoint: RuntimeEndpoint,
+
+    /// Container starts and stops that run at the same time
+    #[structopt(long, env = "EASYHARUN_MAX_PARALLEL_ACTIONS", default_value = "4")]
+    max_parallel_actions: usize,
+
+    /// Seconds exited containers are kept before they are removed
+    #[structopt(long, env = "EASYHARUN_GC_RETENTION_S", default_value = "3600")]
+    gc_retention_s: u64,
+
+    /// Exited containers kept per container regardless of the retention, for debugging
+    #[structopt(long, env = "EASYHARUN_GC_KEEP_EXITED", default_value = "3")]
+    gc_keep_exited: usize,
 }
 
 #[tokio::main]
 pub async fn main() {
 
-    tracing_subscriber::fmt::init();
+    let opt = Opt::from_args();
+
+    tracing_init(&opt.log_level, &opt.log_format).expect("could not init tracing");
 
-    let (config_reader, config_writer) = ConfigProvider::new(ConfigMonitor::load_config().await);
+    let config = ConfigMonitor::load_config(&opt.config).await.expect("could not read config");
+
+    // the namespace is fixed for the life of the process, a reload can not change it.
+    let namespace = config.get_namespace().to_string();
+    let lock_dir = opt.lock_dir.as_ref().map(PathBuf::from).unwrap_or_else(::std::env::temp_dir);
+    let namespace_lock = NamespaceLock::acquire(&lock_dir, &namespace).expect("could not lock the namespace");
+    ::tracing::info!("managing namespace {}, locked {:?}", namespace, namespace_lock.get_path());
+
+    if let RuntimeEndpoint::Http(_) = opt.runtime_endpoint {
+        ::tracing::warn!("the namespace lock only covers this host, an easyharun on another host can still manage namespace {}", namespace);
+    }
+
+    // the lock is held, no other instance of this host writes the state of the namespace.
+    let state_dir = Path::new(&opt.state_dir).join(&namespace);
+    ::std::fs::create_dir_all(&state_dir).expect("could not create state directory");
+
+    let (config_reader, config_writer) = ConfigProvider::new(config);
 
     let (registry_jh, registry_actor) = ActorRegistry::spawn_new();
     registry_actor.register_as_default();
 
+    let config_path = opt.config.clone();
     let jh_config_watch = ::tokio::spawn(async move {
-        ConfigMonitor::async_watch(config_writer).await
+        ConfigMonitor::async_watch(config_path, config_writer).await
     });
 
-    let (mut jh, core) = Core::spawn(config_reader, false);
+    let gc_options = GcOptions {
+        retention_s: opt.gc_retention_s,
+        keep_exited: opt.gc_keep_exited,
+    };
+
+    let runtime = DockerRuntime::connect(&opt.runtime_endpoint).expect("could not connect to the container runtime");
+
+    let kv = KV::open(&state_dir).expect("could not open the state directory");
+
+    let runtime = NamespacedContainerRuntime::new(Arc::new(runtime), &namespace);
+
+    let (mut jh, core) = Core::spawn(config_reader.clone(), Arc::new(runtime), kv, opt.max_parallel_actions, gc_options, false);
 
     ::tokio::select! {
-        _ = actor_run_grpc_server("0.0.0.0:50051", registry_actor.clone()) => {
-            panic!("actor_run_grpc_server crash.");
+        _ = admin_run_grpc_server(&opt.admin_listen, registry_actor.clone(), config_reader.clone(), core.kv.clone()) => {
+            panic!("admin_run_grpc_server crash.");
         }
         _ = jh_config_watch => {
             panic!("config_watch crash.");
@@ -68,8 +152,9 @@ pub async fn main() {
 
 pub struct Core {
     kv: KV,
-    handle_proxymanager: ActorStateHandle<()>,
-    handle_containermanager: ActorStateHandle<()>,
+    handle_container_watcher: ActorStateHandle<ContainerWatcherMsg>,
+    handle_proxymanager: ActorStateHandle<ProxyManagerMsg>,
+    handle_containermanager: ActorStateHandle<ContainerManagerMsg>,
     handle_healh_check_manager: ActorStateHandle<HealthCheckMsgRecv>,
     kill: CancellationToken,
     debug_write: Option<DebugWrite>
@@ -79,6 +164,10 @@ pub struct Core {
 impl Core {
     pub fn spawn(
         config_reader: ConfigReader,
+        runtime: ContainerRuntimeRef,
+        kv: KV,
+        max_parallel_actions: usize,
+        gc_options: GcOptions,
         debug: bool,
     ) -> (JoinHandle<()>, Core) {
 
@@ -97,30 +186,56 @@ impl Core {
             ))
         }
 
-        let kv = KV::new();
+        // the managers list containers on every tick, the watcher answers from its snapshot.
+        let watched_runtime = WatchedContainerRuntime::new(runtime);
+
+        let (jh_container_watcher, handle_container_watcher, _) = Actor::spawn(ActorConfig::new("ContainerWatcher", "Watcher").build(), |actor_state| ContainerWatcher::new(
+            actor_state,
+            watched_runtime.clone(),
+        ));
+
+        let runtime : ContainerRuntimeRef = Arc::new(watched_runtime.clone());
 
         let (jh_proxymanager, handle_proxymanager, _) = Actor::spawn(ActorConfig::new("ProxyManager", "Manager").build(), |actor_state| ProxyManager::new(
             actor_state,
             config_reader.clone(),
+            runtime.clone(),
             kv.clone()
         ));
 
-        let (jh_containermanager, handle_containermanager, _) = Actor::spawn(ActorConfig::new("ContainerManager", "Manager").build(), |actor_state| ContainerManager {
+        let (jh_containermanager, handle_containermanager, _) = Actor::spawn(ActorConfig::new("ContainerManager", "Manager").build(), |actor_state| ContainerManager::new(
             actor_state,
-            config_reader: config_reader.clone(),
-            kv: kv.clone()
-        });
+            config_reader.clone(),
+            runtime.clone(),
+            kv.clone(),
+            max_parallel_actions,
+            gc_options,
+        ));
 
         let (jh_healh_check_manager, handle_healh_check_manager, _) = Actor::spawn(ActorConfig::new("HealthCheckManager", "Manager").build(), |actor_state| HealthCheckManager::new(
             actor_state,
             config_reader.clone(),
+            runtime.clone(),
             kv.clone()
         ));
 
+        // managers react to config changes right away instead of waiting for their next tick.
+        config_reader.subscribe(handle_proxymanager.clone(), ProxyManagerMsg::ConfigChanged);
+        config_reader.subscribe(handle_containermanager.clone(), ContainerManagerMsg::ConfigChanged);
+        config_reader.subscribe(handle_healh_check_manager.clone(), HealthCheckMsgRecv::ConfigChanged);
+
+        // and to containers that started, died or went away.
+        watched_runtime.subscribe(handle_proxymanager.clone(), ProxyManagerMsg::ContainersChanged);
+        watched_runtime.subscribe(handle_containermanager.clone(), ContainerManagerMsg::ContainersChanged);
+        watched_runtime.subscribe(handle_healh_check_manager.clone(), HealthCheckMsgRecv::ContainersChanged);
+
         let kill = CancellationToken::new();
         let kill_moved = kill.clone();
         let jh = ::tokio::spawn(async move {
             ::tokio::select! {
+                _ = jh_container_watcher => {
+                    panic!("container_watcher crash.");
+                }
                  _ = jh_proxymanager => {
                     panic!("proximanager crash.");
                 }
@@ -130,7 +245,7 @@ impl Core {
                 _ = jh_healh_check_manager => {
                     panic!("jh_healh_check_manager crash.");
                 },
-                _ = grpc_debug => {
+                _ = grpc_debug, if debug => {
                     panic!("grpc_debug crash.");
                 },
                 _ = kill_moved.cancelled() => {
@@ -142,6 +257,7 @@ impl Core {
         (jh, Self {
             kv,
             debug_write,
+            handle_container_watcher,
             handle_proxymanager,
             handle_containermanager,
             handle_healh_check_manager,
@@ -156,3 +272,19 @@ impl Drop for Core {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use crate::container_manager::gc::{DEFAULT_GC_KEEP_EXITED, DEFAULT_GC_RETENTION_S};
+    use crate::docker::docker_action_executer::DEFAULT_MAX_PARALLEL_ACTIONS;
+    use super::*;
+
+    #[test]
+    fn option_defaults_match_the_code() {
+        let opt = Opt::from_iter(["easyharun_server"]);
+
+        assert_eq!(DEFAULT_MAX_PARALLEL_ACTIONS, opt.max_parallel_actions);
+        assert_eq!(DEFAULT_GC_RETENTION_S, opt.gc_retention_s);
+        assert_eq!(DEFAULT_GC_KEEP_EXITED, opt.gc_keep_exited);
+    }
+}
@@ -1,3 +1,4 @@
+use std::collections::HashSet;
 use crate::proxy::world::{ProxyWorlds};
 
 pub struct ProxyBrain;
@@ -6,6 +7,18 @@ pub struct ProxyBrain;
 pub enum ProxyBrainAction {
     Add(ProxyBrainActionAdd),
     RemoveAsk(ProxyBrainActionRemove),
+    // switches all servers of a proxy in one step.
+    Replace(ProxyBrainActionReplace),
+}
+
+impl ProxyBrainAction {
+    pub fn get_listen_addr(&self) -> &str {
+        match self {
+            Self::Add(a) => &a.listen_addr,
+            Self::RemoveAsk(a) => &a.listen_addr,
+            Self::Replace(a) => &a.listen_addr,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -20,17 +33,52 @@ pub struct ProxyBrainActionRemove {
     pub server_addr: String,
 }
 
+#[derive(Debug, Clone)]
+pub struct ProxyBrainActionReplace {
+    pub listen_addr: String,
+    pub server_addrs: HashSet<String>,
+}
+
 impl ProxyBrain {
     pub fn think(worlds : &ProxyWorlds) -> Vec<ProxyBrainAction> {
 
         let mut buf = vec![];
 
-        buf.extend(Self::think_about_adding_proxies(worlds));
-        buf.extend(Self::think_about_removing_servers_from_proxy(worlds));
+        let replacements = Self::think_about_replacing_servers(worlds);
+        let replaced = |action: &ProxyBrainAction| replacements.iter().any(|r| r.get_listen_addr() == action.get_listen_addr());
+
+        buf.extend(Self::think_about_adding_proxies(worlds).into_iter().filter(|a| !replaced(a)));
+        buf.extend(Self::think_about_removing_servers_from_proxy(worlds).into_iter().filter(|a| !replaced(a)));
+        buf.extend(replacements);
 
         return buf;
     }
 
+    // when servers are added and removed at the same time (e.g. a blue/green cutover)
+    // the proxy must never route to a mix of both, so all servers are switched at once.
+    fn think_about_replacing_servers(worlds : &ProxyWorlds) -> Vec<ProxyBrainAction> {
+        let mut buf = vec![];
+
+        for (listen_addr, proxy_expected) in worlds.expected.proxies.iter() {
+            let proxy_current = match worlds.current.proxies.get(listen_addr) {
+                Some(s) => s,
+                None => continue,
+            };
+
+            let adds = proxy_expected.server_addrs.difference(&proxy_current.server_addrs).count();
+            let removes = proxy_current.server_addrs.difference(&proxy_expected.server_addrs).count();
+
+            if adds > 0 && removes > 0 {
+                buf.push(ProxyBrainAction::Replace(ProxyBrainActionReplace {
+                    listen_addr: listen_addr.to_string(),
+                    server_addrs: proxy_expected.server_addrs.clone(),
+                }));
+            }
+        }
+
+        buf
+    }
+
     fn think_about_removing_servers_from_proxy(worlds : &ProxyWorlds) -> Vec<ProxyBrainAction> {
         let mut buf = vec![];
 
@@ -96,4 +144,38 @@ impl ProxyBrain {
 
         buf
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use crate::proxy::world::{ProxyWorld, ProxyWorldEntry};
+    use super::*;
+
+    fn proxy_world(server_addrs: &[&str]) -> ProxyWorld {
+        let mut proxies = HashMap::new();
+        proxies.insert("0.0.0.0:80".to_string(), ProxyWorldEntry {
+            listen_addr: "0.0.0.0:80".to_string(),
+            server_addrs: server_addrs.iter().map(|s| s.to_string()).collect(),
+            canary_weights: HashMap::new(),
+        });
+
+        ProxyWorld { proxies }
+    }
+
+    #[test]
+    fn switching_all_servers_is_a_single_action() {
+        let worlds = ProxyWorlds {
+            current: proxy_world(&["127.0.0.1:1", "127.0.0.1:2"]),
+            expected: proxy_world(&["127.0.0.1:3", "127.0.0.1:4"]),
+        };
+
+        let actions = ProxyBrain::think(&worlds);
+
+        assert_eq!(1, actions.len());
+        match &actions[0] {
+            ProxyBrainAction::Replace(r) => assert_eq!(proxy_world(&["127.0.0.1:3", "127.0.0.1:4"]).proxies["0.0.0.0:80"].server_addrs, r.server_addrs),
+            a => panic!("unexpected action {:?}", a),
+        };
+    }
+}
@@ -2,4 +2,13 @@ pub mod proxy_implementation;
 pub mod docker;
 pub mod world;
 pub mod proxy_manager;
-pub mod brain;
\ No newline at end of file
+pub mod brain;
+
+use crate::config::config_provider::ConfigChanged;
+use crate::container_watcher::ContainersChanged;
+
+#[derive(Debug)]
+pub enum ProxyManagerMsg {
+    ConfigChanged(ConfigChanged),
+    ContainersChanged(ContainersChanged),
+}
\ No newline at end of file
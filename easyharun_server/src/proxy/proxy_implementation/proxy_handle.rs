@@ -39,6 +39,9 @@ impl ProxyHandle {
             &ProxyBrainAction::RemoveAsk(ref v) => {
                 self.server_addrs.remove(&v.server_addr);
             },
+            ProxyBrainAction::Replace(v) => {
+                self.server_addrs = v.server_addrs.clone();
+            },
         };
 
         Ok(
@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use tokio::io;
 use tokio::io::AsyncWriteExt;
 use tokio::net::{TcpListener, TcpStream};
@@ -12,14 +13,17 @@ use easyact::actor::ActorTask::{ActorTask, ActorTaskConfig, ActorTaskState};
 
 use crate::kv_container::KV;
 
-use crate::proxy::brain::{ProxyBrainAction, ProxyBrainActionAdd, ProxyBrainActionRemove};
+use crate::proxy::brain::{ProxyBrainAction, ProxyBrainActionAdd, ProxyBrainActionRemove, ProxyBrainActionReplace};
 use crate::proxy::proxy_implementation::proxy_handle::{ProxyHandle};
+use crate::proxy::world::get_server_weights;
 
 
 pub struct TcpProxy {
     listen_addr: String,
     server_addrs: Vec<String>,
     stats_requests_all: u64,
+    // smooth weighted round robin state of the canary split, by server addr.
+    current_weights: HashMap<String, i64>,
     recv: UnboundedReceiver<ProxyBrainAction>,
     actor_state: ActorTaskState,
     kv: KV,
@@ -74,6 +78,7 @@ impl TcpProxy {
             listen_addr: listen_addr_clone,
             server_addrs: vec![],
             stats_requests_all: 0,
+            current_weights: HashMap::new(),
             recv,
             actor_state,
             kv
@@ -90,6 +95,7 @@ impl TcpProxy {
         match action {
             ProxyBrainAction::Add(action) => self.handle_action_add(action),
             ProxyBrainAction::RemoveAsk(action) => self.handle_action_remove(action),
+            ProxyBrainAction::Replace(action) => self.handle_action_replace(action),
         };
     }
 
@@ -105,15 +111,41 @@ impl TcpProxy {
         self.server_addrs.retain(|x| x != &action.server_addr);
     }
 
-    async fn pick_server(&self) -> Result<String, ::anyhow::Error> {
+    fn handle_action_replace(&mut self, action : ProxyBrainActionReplace) {
+        self.server_addrs = action.server_addrs.into_iter().collect();
+        self.server_addrs.sort();
+    }
+
+    async fn pick_server(&mut self) -> Result<String, ::anyhow::Error> {
         let server_addrs_len = self.server_addrs.len() as u64;
 
         if server_addrs_len == 0 {
             return Err(anyhow!("no backend server..."));
         }
 
+        // with a canary, servers are picked by their weight instead of round robin.
+        if let Some(canary_weights) = self.kv.get_proxy_canary_weights(&self.listen_addr).await {
+            let mut healthy = vec![];
+            for server in self.server_addrs.iter() {
+                if self.kv.is_backend_ready(server).await {
+                    healthy.push(server);
+                }
+            }
+
+            let candidates = match healthy.is_empty() {
+                true => self.server_addrs.iter().collect::<Vec<_>>(),
+                false => healthy,
+            };
+
+            let server_weights = get_server_weights(&candidates, &canary_weights);
 
-        // we pick the first server that is healthy // todo
+            if let Some(server) = pick_smooth_weighted(&mut self.current_weights, &candidates, &server_weights) {
+                return Ok(server);
+            }
+        }
+
+
+        // we pick the next server that is ready.
         for i in 0..server_addrs_len {
 
             let id = ((self.stats_requests_all + i) % server_addrs_len) as usize;
@@ -121,7 +153,7 @@ impl TcpProxy {
 
 
             // todo, the read lock might be expensive.
-            if !self.kv.is_target_healthy(server).await {
+            if !self.kv.is_backend_ready(server).await {
                 info!("skip (unhealthy) {}", server);
                 continue;
             }
@@ -148,17 +180,22 @@ impl TcpProxy {
 
         let backend_server_addr = self.pick_server().await.context("could not pick a backend server")?;
 
+        let kv = self.kv.clone();
+
         ::tokio::spawn(async move {
 
             println!("accept {backend_server_addr}");
 
-            match Self::transfer(inbound, backend_server_addr).await {
+            let ok = match Self::transfer(inbound, backend_server_addr.clone()).await {
                 Err(e) => {
                     println!("Failed to transfer; error={}", e);
+                    false
                 }
-                Ok(_) => {}
+                Ok(_) => true
             };
 
+            kv.record_proxy_connection(&backend_server_addr, ok).await;
+
         });
 
         Ok(())
@@ -184,4 +221,49 @@ impl TcpProxy {
 
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+// smooth weighted round robin, like nginx. every candidate gains its weight, the one with the most
+// current weight is picked and pays the total. a canary at 10% gets every 10th connection instead of a block of 10.
+fn pick_smooth_weighted(current_weights: &mut HashMap<String, i64>, candidates: &[&String], server_weights: &HashMap<String, u32>) -> Option<String> {
+    current_weights.retain(|server, _| candidates.contains(&server));
+
+    let mut total_weight = 0;
+    let mut picked: Option<(&String, i64)> = None;
+
+    for server in candidates {
+        let weight = server_weights.get(*server).copied().unwrap_or_default() as i64;
+        let current_weight = current_weights.entry(server.to_string()).or_default();
+        *current_weight += weight;
+        total_weight += weight;
+
+        if picked.map(|(_, w)| *current_weight > w).unwrap_or(true) {
+            picked = Some((server, *current_weight));
+        }
+    }
+
+    if total_weight == 0 {
+        return None;
+    }
+
+    let (server, _) = picked?;
+    *current_weights.get_mut(server)? -= total_weight;
+    Some(server.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn canary_connections_are_spread() {
+        let servers = ["a".to_string(), "b".to_string()];
+        let candidates = servers.iter().collect::<Vec<_>>();
+        let server_weights = HashMap::from([("a".to_string(), 1), ("b".to_string(), 3)]);
+        let mut current_weights = HashMap::new();
+
+        let picked = (0..8).map(|_| pick_smooth_weighted(&mut current_weights, &candidates, &server_weights).expect("picked")).collect::<Vec<_>>();
+
+        assert_eq!(vec!["b", "a", "b", "b", "b", "a", "b", "b"], picked);
+    }
+}
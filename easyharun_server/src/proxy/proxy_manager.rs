@@ -4,18 +4,18 @@ use std::collections::{HashMap, HashSet};
 use std::time::Duration;
 use anyhow::{Context, Error};
 use async_trait::async_trait;
-use bollard::container::ListContainersOptions;
 use tracing::{info, warn};
 use easyact::{Actor, ActorState};
 use easyharun_lib::config::Config;
 
 use easyharun_lib::portmapping::{PortMapping};
-use crate::config::config_provider::{ConfigReader};
-use crate::docker::docker_connection::docker_create_connection;
+use crate::config::config_provider::ConfigReader;
+use crate::container_runtime::ContainerRuntimeRef;
 use crate::docker::docker_world_builder::{build_world_container, docker_container_info, PortInternalDynamic};
 use crate::kv_container::KV;
 
-use crate::proxy::brain::{ProxyBrain, ProxyBrainAction, ProxyBrainActionAdd, ProxyBrainActionRemove};
+use crate::proxy::ProxyManagerMsg;
+use crate::proxy::brain::{ProxyBrain, ProxyBrainAction, ProxyBrainActionAdd, ProxyBrainActionRemove, ProxyBrainActionReplace};
 use crate::proxy::proxy_implementation::proxy_handle::ProxyHandle;
 use crate::proxy::proxy_implementation::tcp_proxy::proxy::TcpProxy;
 use crate::proxy::world::{ProxyWorld, ProxyWorldEntry, ProxyWorlds};
@@ -24,14 +24,16 @@ use crate::proxy::world::{ProxyWorld, ProxyWorldEntry, ProxyWorlds};
 #[derive(Debug)]
 pub struct ProxyManager {
     proxies: HashMap<String, ProxyHandle>,
-    actor_state: ActorState<()>,
+    actor_state: ActorState<ProxyManagerMsg>,
     config_reader: ConfigReader,
+    config_generation_applied: u64,
+    runtime: ContainerRuntimeRef,
     kv: KV,
 }
 
 #[async_trait]
 impl Actor for ProxyManager {
-    type MSG = ();
+    type MSG = ProxyManagerMsg;
 
     fn get_actor_state(&mut self) -> &mut ActorState<Self::MSG> {
         &mut self.actor_state
@@ -46,7 +48,7 @@ impl Actor for ProxyManager {
     }
 
     async fn on_msg(&mut self, msg: Self::MSG) -> Result<(), Error> {
-        Ok(())
+        self.run_inner().await
     }
 }
 
@@ -59,7 +61,8 @@ impl ProxyManager {
         for (_,proxy) in self.proxies.iter() {
             buf.insert(proxy.get_listen_addr().to_string(), ProxyWorldEntry {
                 listen_addr: proxy.get_listen_addr().to_string(),
-                server_addrs: proxy.get_server_addrs().clone()
+                server_addrs: proxy.get_server_addrs().clone(),
+                canary_weights: HashMap::new(),
             });
         }
 
@@ -70,24 +73,19 @@ impl ProxyManager {
 
     async fn create_proxy_world_expected(&self, config : &Config) -> Result<ProxyWorld, ::anyhow::Error> {
 
-        let docker = docker_create_connection().context("docker connection?")?;
-
-        let filters : HashMap<String, Vec<String>> = HashMap::new();
-        // filters.insert("label", vec!["easyharun=\"1.0.0\""]);
-
-        let containers = docker.list_containers(Some(ListContainersOptions {
-            all: true,
-            size: false,
-            limit: None,
-            filters
-        })).await.context("could not read containers from docker container")?;
+        let containers = self.runtime.list_containers().await?;
 
         let mut proxies : HashMap<String, ProxyWorldEntry> = HashMap::new();
+        let mut health_checked_backends = HashSet::new();
+
+        let canary_weights = self.kv.get_canary_status().await.into_iter()
+            .map(|c| (c.revision, c.weight))
+            .collect::<HashMap<_, _>>();
 
         for container in containers.iter() {
 
             let container_id = match docker_container_info(&container, &self.kv).await {
-                Some(s) => s.container_id,
+                Some(s) => s.identity.id,
                 None => {
                     continue
                 }
@@ -100,6 +98,13 @@ impl ProxyManager {
                 }
             };
 
+            // blue/green containers only get traffic while their revision is live.
+            if let Some(live_revision) = self.kv.get_live_revision(&container_world.name).await {
+                if container_world.revision.as_ref() != Some(&live_revision) {
+                    continue;
+                }
+            }
+
             let port_mappings = match container_world.container_port_mapping {
                 Some(s) => s,
                 None => {
@@ -108,6 +113,10 @@ impl ProxyManager {
                 }
             };
 
+            if !container_world.health_checks.is_empty() {
+                health_checked_backends.extend(port_mappings.iter().map(|p| p.get_server_addr()));
+            }
+
             for container_config_proxy in container_world.proxies {
 
                 let config_proxy = match config.proxy.iter().find(|c|&c.name == &container_config_proxy.name) {
@@ -128,12 +137,14 @@ impl ProxyManager {
 
                 let portmapping = PortMapping {
                     listen_addr: config_proxy.listen.to_string(),
-                    server_addr: format!("127.0.0.1:{}", dynamic_port.dynamic),
+                    server_addr: dynamic_port.get_server_addr(),
                 };
 
-                match proxies.entry(portmapping.listen_addr.to_string()) {
-                    Occupied(mut o) => {
-                        o.get_mut().server_addrs.insert(portmapping.server_addr.to_string());
+                let proxy = match proxies.entry(portmapping.listen_addr.to_string()) {
+                    Occupied(o) => {
+                        let proxy = o.into_mut();
+                        proxy.server_addrs.insert(portmapping.server_addr.to_string());
+                        proxy
                     },
                     Vacant(o) => {
                         o.insert(ProxyWorldEntry {
@@ -142,47 +153,71 @@ impl ProxyManager {
                                 let mut s = HashSet::new();
                                 s.insert(portmapping.server_addr.clone());
                                 s
-                            }
-                        });
+                            },
+                            canary_weights: HashMap::new(),
+                        })
                     },
                 };
 
+                if let Some(weight) = container_world.revision.as_ref().and_then(|r| canary_weights.get(r)) {
+                    proxy.canary_weights.insert(portmapping.server_addr.to_string(), *weight);
+                }
+
             }
 
         }
 
+        // set before the proxies route to a new container, its checks may not be registered yet.
+        self.kv.set_health_checked_backends(health_checked_backends).await;
+
         Ok(ProxyWorld {
             proxies
         })
     }
 
-    pub fn new(actor_state: ActorState<()>, config_reader: ConfigReader, kv: KV) -> Self {
+    pub fn new(actor_state: ActorState<ProxyManagerMsg>, config_reader: ConfigReader, runtime: ContainerRuntimeRef, kv: KV) -> Self {
         Self {
             actor_state,
             proxies: HashMap::new(),
             config_reader,
+            config_generation_applied: 0,
+            runtime,
             kv
         }
     }
 
     pub async fn run_inner(&mut self) -> Result<(), ::anyhow::Error> {
 
-        let config =self.config_reader.get_copy().await;
+        let config_snapshot = self.config_reader.get_snapshot();
 
         let worlds = ProxyWorlds {
             current: self.create_proxy_world_current(),
-            expected: self.create_proxy_world_expected(&config).await?
+            expected: self.create_proxy_world_expected(&config_snapshot.config).await?
         };
 
         let actions = ProxyBrain::think(&worlds);
 
         match self.execute_brain_actions(actions).await {
-            Ok(_) => {},
+            Ok(_) => {
+                if self.config_generation_applied != config_snapshot.generation {
+                    self.config_generation_applied = config_snapshot.generation;
+                    self.config_reader.set_applied_generation("ProxyManager", config_snapshot.generation).await;
+                }
+            },
             Err(_) => {
                 eprintln!("failed to execute brain actions.");
             }
         };
 
+        for (listen_addr, proxy) in worlds.expected.proxies.iter() {
+            self.kv.set_proxy_canary_weights(listen_addr, proxy.canary_weights.clone()).await;
+        }
+
+        // the container manager waits until a container is not used by any proxy before it stops it.
+        for (listen_addr, proxy) in self.proxies.iter() {
+            self.kv.set_proxy_server_addrs(listen_addr, proxy.get_server_addrs().clone()).await;
+        }
+
         Ok(())
     }
 
@@ -210,6 +245,7 @@ impl ProxyManager {
         Ok(match action {
             ProxyBrainAction::Add(a) => self.execute_brain_actions_add(a).await?,
             ProxyBrainAction::RemoveAsk(a) => self.execute_brain_actions_remove_ask(a).await?,
+            ProxyBrainAction::Replace(a) => self.execute_brain_actions_replace(a).await?,
         })
     }
 
@@ -232,6 +268,21 @@ impl ProxyManager {
         Ok(())
     }
 
+    pub async fn execute_brain_actions_replace(&mut self, action : ProxyBrainActionReplace) -> Result<(), ::anyhow::Error> {
+
+        match self.proxies.get_mut(&action.listen_addr) {
+            Some(proxy) => {
+                info!(listen_addr = action.listen_addr, server_addrs = ?action.server_addrs, "switching servers of proxy");
+                proxy.send(ProxyBrainAction::Replace(action))?;
+            },
+            None => {
+                warn!(listen_addr = action.listen_addr, "switching servers of proxy (but proxy does not exists)");
+            },
+        };
+
+        Ok(())
+    }
+
     pub async fn execute_brain_actions_remove_ask(&mut self, action : ProxyBrainActionRemove) -> Result<(), ::anyhow::Error> {
 
         match self.proxies.entry(action.listen_addr.to_string()) {
@@ -12,6 +12,8 @@ pub struct ProxyWorld {
 pub struct ProxyWorldEntry {
     pub listen_addr: String,
     pub server_addrs: HashSet<String>,
+    // percent of the traffic by server addr, for servers of a canary.
+    pub canary_weights: HashMap<String, u32>,
 }
 
 impl ProxyWorlds {
@@ -20,4 +22,48 @@ impl ProxyWorlds {
 
 impl ProxyWorld {
 
+}
+
+// canary servers share their percent of the traffic, the other servers share the rest.
+// the proxy passes the servers that are ready, the shares stay the same when a server drops out.
+// empty if there is no canary, every server gets the same share then.
+pub fn get_server_weights(server_addrs: &[&String], canary_weights: &HashMap<String, u32>) -> HashMap<String, u32> {
+    let canary_servers = server_addrs.iter().filter(|s| canary_weights.contains_key(**s)).count() as u32;
+    let other_servers = server_addrs.len() as u32 - canary_servers;
+
+    if canary_servers == 0 {
+        return HashMap::new();
+    }
+
+    server_addrs.iter().map(|server_addr| {
+        let weight = match canary_weights.get(*server_addr) {
+            Some(weight) => weight * other_servers.max(1),
+            None => (100 - canary_weights.values().max().copied().unwrap_or_default()) * canary_servers,
+        };
+
+        (server_addr.to_string(), weight)
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn canary_servers_share_their_weight() {
+        let server_addrs = ["127.0.0.1:1", "127.0.0.1:2", "127.0.0.1:3"].iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let canary_weights = HashMap::from([("127.0.0.1:3".to_string(), 10)]);
+
+        let weights = get_server_weights(&server_addrs.iter().collect::<Vec<_>>(), &canary_weights);
+
+        // 10% for the canary, 45% for each of the other two servers.
+        assert_eq!(20, weights["127.0.0.1:3"]);
+        assert_eq!(90, weights["127.0.0.1:1"]);
+        assert_eq!(90, weights["127.0.0.1:2"]);
+
+        // the canary keeps its 10% when a server is not ready.
+        let weights = get_server_weights(&[&server_addrs[0], &server_addrs[2]], &canary_weights);
+        assert_eq!(10, weights["127.0.0.1:3"]);
+        assert_eq!(90, weights["127.0.0.1:1"]);
+    }
 }
\ No newline at end of file
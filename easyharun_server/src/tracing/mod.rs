@@ -1,7 +1,22 @@
 use std::io::Write;
 use std::sync::{Arc, Mutex};
+use anyhow::{anyhow, Context};
+use tracing_subscriber::EnvFilter;
 use tracing_subscriber::fmt::MakeWriter;
 
+// log_level accepts everything RUST_LOG accepts, e.g. "info" or "easyharun_server=debug,warn".
+pub fn tracing_init(log_level: &str, log_format: &str) -> Result<(), ::anyhow::Error> {
+    let env_filter = EnvFilter::try_new(log_level).context(format!("invalid log level {}", log_level))?;
+
+    match log_format {
+        "text" => tracing_subscriber::fmt().with_env_filter(env_filter).init(),
+        "json" => tracing_subscriber::fmt().json().with_env_filter(env_filter).init(),
+        _ => return Err(anyhow!("unknown log format {}", log_format)),
+    };
+
+    Ok(())
+}
+
 #[derive(Clone, Debug)]
 pub struct DebugWrite {
     data: Arc<Mutex<Vec<u8>>>,